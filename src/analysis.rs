@@ -0,0 +1,123 @@
+use std::fs;
+use std::path::Path;
+
+use crate::bitboard::Board;
+use crate::cmds::{idx_to_move, move_to_idx, move_to_string, Color, Move};
+use crate::evaluator::Evaluator;
+use crate::game::Game;
+use crate::score;
+use crate::search::{search_multipv, Bound, SearchContext};
+
+/// 棋譜 (`Game::from_transcript`の形式) か、それを書いたファイルから対局を読む
+pub fn game_from_moves(moves: &str) -> Result<Game, String> {
+    let path = Path::new(moves);
+    if path.is_file() {
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Game::from_transcript(&text).map_err(|e| format!("{}: {}", path.display(), e))
+    } else {
+        Game::from_transcript(moves)
+    }
+}
+
+/// 局面文字列 (`Board`の`FromStr`の形式) か、それを書いたファイルから盤面を読む
+pub fn board_from_position(position: &str) -> Result<Board, String> {
+    let path = Path::new(position);
+    if path.is_file() {
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        text.parse().map_err(|e| format!("{}: {}", path.display(), e))
+    } else {
+        position.parse()
+    }
+}
+
+/// コマンドの引数 (棋譜か局面文字列と、そこから戻す手数) が指す局面
+pub fn position_from_args(moves: &str, position: Option<&str>, back: usize) -> Result<Board, String> {
+    let mut game = match position {
+        Some(position) => Game::from_board(board_from_position(position)?),
+        None => game_from_moves(moves)?,
+    };
+    for _ in 0..back {
+        game.undo();
+    }
+    Ok(game.board().clone())
+}
+
+/// 局面の合法手を上位n手まで (n=0なら全部) 採点して表示する
+pub fn analyze(board: &Board, depth: usize, n: usize, ctx: &mut SearchContext) {
+    board.print();
+    println!("depth {}", depth);
+    let results = search_multipv(board, depth, n, ctx);
+    if results.is_empty() {
+        println!("No legal moves (pass)");
+    }
+    for (i, r) in results.iter().enumerate() {
+        let pv: Vec<String> = r.pv.iter().map(|mv| move_to_string(idx_to_move(mv))).collect();
+        let score = match r.bound {
+            Bound::Exact => format!("{:>10} ({:>3.0}%)", score::format(r.score), score::win_probability(r.score) * 100.0),
+            Bound::Upper => format!("<={:>8}       ", score::format(r.score)),
+        };
+        println!("{:>2}. {} {}  {}", i + 1, move_to_string(idx_to_move(&r.mv)), score, pv.join(" "));
+    }
+}
+
+/// 局面の評価値の内訳を項目ごとに表示し、マスごとの寄与を盤面の上に並べる
+pub fn explain(board: &Board, evaluator: &dyn Evaluator) {
+    let (me, op) = if board.side() == Color::Black { ('X', 'O') } else { ('O', 'X') };
+    let player_moves = board.legals().0;
+    let mut opponent = board.clone();
+    opponent.exchange();
+    let opponent_moves = opponent.legals().0;
+    let empties = 64 - (board.player() | board.opponent()).count_ones();
+
+    board.print();
+    println!("evaluator: {} ({} empties), from {}'s point of view", evaluator.name(), empties, me);
+    let terms = evaluator.explain(board, player_moves, opponent_moves);
+    println!("{:<20} {:>7} {:>7} {:>7} {:>8}", "term", "weight", me, op, "score");
+    let mut total = 0;
+    let mut squares = [0; 64];
+    for term in terms.iter() {
+        println!("{:<20} {:>7} {:>7} {:>7} {:>+8}", term.name, term.weight, term.mine, term.theirs, term.score());
+        total += term.score();
+        for (sum, square) in squares.iter_mut().zip(term.squares.iter()) {
+            *sum += square;
+        }
+    }
+    println!("{:<20} {:>32}", "total", format!("{:+}", total));
+    // 項目の寄与は倍率を掛ける前の値
+    let scale = evaluator.scale();
+    if scale != score::SCALE_ONE {
+        println!("{:<20} {:>32}", "scale", format!("x{:.3}", scale as f64 / score::SCALE_ONE as f64));
+    }
+    let score = evaluator.evaluate(board, player_moves, opponent_moves);
+    if score != total {
+        println!("{:<20} {:>32}", "evaluate", format!("{:+}", score));
+    }
+    println!("{:<20} {:>32}", "discs", score::format(score));
+    println!("{:<20} {:>31.0}%", "win probability", score::win_probability(score) * 100.0);
+
+    // 石の文字の後にそのマスの寄与を書く (寄与のないマスは.)
+    let before_scale = if scale != score::SCALE_ONE { ", before scale" } else { "" };
+    println!("\nper square ({}'s point of view{})", me, before_scale);
+    print!(" |");
+    for col in "ABCDEFGH".chars() {
+        print!("{:>7}", col);
+    }
+    println!();
+    for row in 1..=8 {
+        print!("{}|", row);
+        for col in 1..=8 {
+            let mask = move_to_idx(&Move::To(col, row));
+            let disc = if board.black() & mask != 0 {
+                'X'
+            } else if board.white() & mask != 0 {
+                'O'
+            } else {
+                '-'
+            };
+            let value = squares[mask.trailing_zeros() as usize];
+            let value = if value == 0 { ".".to_string() } else { format!("{:+}", value) };
+            print!(" {}{:>5}", disc, value);
+        }
+        println!();
+    }
+}
@@ -0,0 +1,93 @@
+use std::path::Path;
+
+use crate::cmds::Color;
+use crate::engine::Engine;
+use crate::game::Game;
+
+// 途中までの対局を最後まで打つ
+fn play_game(black: &mut Engine, white: &mut Engine, mut game: Game) -> Game {
+    while !game.is_over() {
+        let engine = if game.side() == Color::Black { &mut *black } else { &mut *white };
+        let (mv, _) = engine.select_move(game.board(), 0);
+        game.play(mv).expect("engine played an illegal move");
+    }
+    game
+}
+
+/// 二つのエンジンを手元で対戦させ、結果を表示する。先後は一局ごとに入れ替える
+/// transcriptsを指定すると、各局の棋譜を種とともにそのファイルへ追記する
+pub fn run_match(first: &mut Engine, second: &mut Engine, games: usize, transcripts: Option<&Path>) {
+    let (name1, name2) = (first.name(), second.name());
+    let (mut wins, mut losses, mut draws) = (0, 0, 0);
+    let mut discs: i64 = 0;
+    for g in 0..games {
+        let seed1 = first.new_game();
+        let seed2 = second.new_game();
+        let first_black = g % 2 == 0;
+        let game = if first_black {
+            play_game(first, second, Game::new())
+        } else {
+            play_game(second, first, Game::new())
+        };
+        let (b, w) = game.score();
+        let (mine, theirs) = if first_black { (b, w) } else { (w, b) };
+        let first_color = if first_black { Color::Black } else { Color::White };
+        match game.winner() {
+            Some(Color::Empty) => draws += 1,
+            Some(color) if color == first_color => wins += 1,
+            _ => losses += 1,
+        }
+        discs += mine as i64 - theirs as i64;
+        let (black_name, white_name) = if first_black { (&name1, &name2) } else { (&name2, &name1) };
+        println!(
+            "Game {}: {} (X) {} - {} {} (O) [seed: {}, {}]",
+            g + 1, black_name, b, w, white_name, seed1, seed2
+        );
+        if let Some(path) = transcripts {
+            let (black_seed, white_seed) = if first_black { (seed1, seed2) } else { (seed2, seed1) };
+            game.append_transcript(path, &[
+                ("black", format!("{} (seed: {})", black_name, black_seed)),
+                ("white", format!("{} (seed: {})", white_name, white_seed)),
+                ("result", format!("{} - {}", b, w)),
+            ]);
+        }
+    }
+    println!(
+        "{} vs {}: Win {}, Lose {}, Draw {} (disc diff {:+})",
+        name1, name2, wins, losses, draws, discs
+    );
+}
+
+/// 対戦の結果 (firstから見た勝ち・負け・引き分けの数と石差の合計)
+pub struct MatchResult {
+    pub wins: usize,
+    pub losses: usize,
+    pub draws: usize,
+    pub discs: i64,
+}
+
+/// openingsの各局面から先後を入れ替えて二局ずつ打たせ、結果を返す (一局ごとの表示はしない)
+/// 決まった序盤から打つと同じ対局ばかりになるので、ランダムに打った序盤を渡して対局をばらつかせる
+pub fn run_openings(first: &mut Engine, second: &mut Engine, openings: &[Game]) -> MatchResult {
+    let mut result = MatchResult { wins: 0, losses: 0, draws: 0, discs: 0 };
+    for opening in openings {
+        for first_black in [true, false] {
+            first.new_game();
+            second.new_game();
+            let game = if first_black {
+                play_game(first, second, opening.clone())
+            } else {
+                play_game(second, first, opening.clone())
+            };
+            let (b, w) = game.score();
+            let diff = if first_black { b as i64 - w as i64 } else { w as i64 - b as i64 };
+            match diff.signum() {
+                1 => result.wins += 1,
+                -1 => result.losses += 1,
+                _ => result.draws += 1,
+            }
+            result.discs += diff;
+        }
+    }
+    result
+}
@@ -0,0 +1,45 @@
+use std::time::Instant;
+
+use crate::game::Game;
+use crate::score;
+use crate::search::{alpha_beta, SearchContext};
+
+// ベンチマークに使う局面 (初期盤面からの棋譜)。序盤から終盤まで手数を散らしてある
+const POSITIONS: [&str; 6] = [
+    "",
+    "d3c3b3c5d6b2b5e3f5c6c7b6",
+    "e6f4c3d6f6d3c5g6g7c6c7b2e3h8h6d7f5f7e8b5",
+    "f5d6c7f6f7e3c3g6g5e8h6f4c5b6e6b8e2d2e1e7f3g3g2h5d7g1d3g7",
+    "e6f4e3f6g5c5c4e2g7g4d2b4c3e7b3h4c6b2e8c7a5f5f7d3d6c2c1g3f2d1b6b1h6b7b8a8",
+    "c4c3f5d6c2b4c5d2d3b2b1c1d7e3a4g6b3a1e1f6e2c6d1f2f1a5a6a3f3d8e6g2g4e7a2h3h2g1c7c8e8f7g8f8",
+];
+
+/// 決まった局面を決まった深さで探索し、局面数と速さ (nps) を表示する
+/// 探索の順序は乱数に依存するので、比べるときは同じ`--seed`を使うこと
+pub fn run_bench(depth: usize, ctx: &mut SearchContext) {
+    let mut total_nodes = 0;
+    let start = Instant::now();
+    for (i, moves) in POSITIONS.iter().enumerate() {
+        let mut board = Game::from_transcript(moves).expect("invalid bench position").board().clone();
+        ctx.nodes = 0;
+        let t = Instant::now();
+        let score = alpha_beta(&mut board, -score::INFINITY, score::INFINITY, depth, false, ctx);
+        let elapsed = t.elapsed();
+        println!(
+            "{:>2}. {:>2} empties  score {:>9}  nodes {:>10}  {:>6} ms",
+            i + 1,
+            64 - board.turns,
+            score::format(score),
+            ctx.nodes,
+            elapsed.as_millis()
+        );
+        total_nodes += ctx.nodes;
+    }
+    let elapsed = start.elapsed();
+    println!(
+        "Total: {} nodes, {} ms, {:.0} nps",
+        total_nodes,
+        elapsed.as_millis(),
+        total_nodes as f64 / elapsed.as_secs_f64().max(1e-9)
+    );
+}
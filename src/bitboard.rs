@@ -1,10 +1,83 @@
-use crate::evaluator;
+use crate::cmds::{move_to_idx, Color, Move};
+use crate::score::{self, Score};
+use std::fmt;
+use std::hash::{BuildHasherDefault, Hasher};
+use std::str::FromStr;
 
-#[derive(Clone, Debug)]
+#[derive(Clone, PartialEq, Eq, Debug)]
 pub struct Board {
-    black: u64,
-    white: u64,
-    pub turns: usize
+    // 手番側の石
+    player: u64,
+    // 相手の石
+    opponent: u64,
+    pub turns: usize,
+    // 手番側の本当の色 (exchangeのたびに入れ替わる)
+    side: Color,
+    // Zobristハッシュ値 (nextとexchangeで差分更新する)
+    hash: u64,
+}
+
+// Zobristハッシュの乱数表をコンパイル時にsplitmix64で作る
+const fn splitmix64(state: u64) -> (u64, u64) {
+    let state = state.wrapping_add(0x9e3779b97f4a7c15);
+    let mut z = state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    (state, z ^ (z >> 31))
+}
+
+const fn zobrist_table() -> ([[u64; 64]; 2], u64) {
+    let mut table = [[0; 64]; 2];
+    let mut state = 0x2545f4914f6cdd1d;
+    let mut color = 0;
+    while color < 2 {
+        let mut sq = 0;
+        while sq < 64 {
+            let (next, z) = splitmix64(state);
+            state = next;
+            table[color][sq] = z;
+            sq += 1;
+        }
+        color += 1;
+    }
+    let (_, side) = splitmix64(state);
+    (table, side)
+}
+
+// ZOBRIST[0]が黒石、ZOBRIST[1]が白石。白番のときはZOBRIST_SIDEも混ぜる
+const ZOBRIST_TABLE: ([[u64; 64]; 2], u64) = zobrist_table();
+const ZOBRIST: [[u64; 64]; 2] = ZOBRIST_TABLE.0;
+const ZOBRIST_SIDE: u64 = ZOBRIST_TABLE.1;
+
+/// Zobristハッシュ値をそのままキーに使うためのHasher (SipHashを通さない)
+#[derive(Default)]
+pub struct ZobristHasher(u64);
+
+impl Hasher for ZobristHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.0 = self.0.rotate_left(8) ^ b as u64;
+        }
+    }
+
+    fn write_u64(&mut self, n: u64) {
+        self.0 = n;
+    }
+}
+
+/// Zobristハッシュ値をキーとするHashMap用
+pub type BuildZobristHasher = BuildHasherDefault<ZobristHasher>;
+
+/// make_moveで打った手を戻すための記録 (mv=0はパス)
+#[derive(Clone, Copy, Debug)]
+pub struct Undo {
+    pub mv: u64,
+    /// 裏返った石
+    pub flipped: u64,
 }
 
 #[inline]
@@ -25,8 +98,6 @@ impl Board {
         (9, 0x007e7e7e7e7e7e00)
     ];
 
-    pub const MAX_EVAL: i32 = i32::MAX;
-
     // Trans
     /*
     pub fn get_opponent_legals(&self) -> u64 {
@@ -38,108 +109,126 @@ impl Board {
     */
 
     pub fn new() -> Board {
-        Board{black: put(put(0, 3, 4), 4, 3),
-              white: put(put(0, 3, 3), 4, 4),
-              turns: 4}
+        let black = put(put(0, 3, 4), 4, 3);
+        let white = put(put(0, 3, 3), 4, 4);
+        Board{player: black,
+              opponent: white,
+              turns: 4,
+              side: Color::Black,
+              hash: Board::compute_hash(black, white, Color::Black)}
     }
 
-    pub fn clear(&mut self) {
-        self.black = put(put(0, 3, 4), 4, 3);
-        self.white = put(put(0, 3, 3), 4, 4);
-        self.turns = 4;
+    /// 黒石・白石・手番から盤面を作る (手数は石の数から決める)
+    pub fn from_discs(black: u64, white: u64, side: Color) -> Board {
+        debug_assert!(black & white == 0);
+        let (player, opponent) = if side == Color::White { (white, black) } else { (black, white) };
+        Board{player,
+              opponent,
+              turns: (black | white).count_ones() as usize,
+              side,
+              hash: Board::compute_hash(player, opponent, side)}
     }
 
-    #[allow(dead_code)]
-    pub fn print(&self) {
-        println!(" |A B C D E F G H");
-        println!("-+---------------");
-        for i in 0..8 {
-            print!("{}|", i + 1);
-            for j in 0..8 {
-                let mask = (1 as u64) << ((7 - i) * 8 + (7 - j));
-                if self.black & mask != 0 {
-                    print!("X ");
-                } else if self.white & mask != 0 {
-                    print!("O ");
-                } else {
-                    print!("  ");
-                }
+    // ハッシュ値を一から計算する
+    fn compute_hash(player: u64, opponent: u64, side: Color) -> u64 {
+        let (b, w) = if side == Color::White { (opponent, player) } else { (player, opponent) };
+        let mut hash = if side == Color::White { ZOBRIST_SIDE } else { 0 };
+        for (color, mut bits) in [(0, b), (1, w)] {
+            while bits != 0 {
+                hash ^= ZOBRIST[color][bits.trailing_zeros() as usize];
+                bits &= bits - 1;
             }
-            println!();
         }
+        hash
     }
 
-    pub fn exchange(&mut self) {
-        let tmp = self.white.clone();
-        self.white = self.black.clone();
-        self.black = tmp;
-    }
-
-    pub fn evaluate(&self, black_mvs: u64, white_mvs: u64) -> i32 {
-        // current version (relatively low performance but fast)
-        
-        if self.white == 0 {
-            Board::MAX_EVAL
-        } else if self.black == 0 {
-            -Board::MAX_EVAL
-        } else {
-            #[inline]
-            fn eval(stones: u64, mvs: u64) -> i32 {
-                const CORNER: u64 = 0x81000000000081;
-                const NEAR_CORNER: u64 = 0b_01000010_11000011_00000000_00000000_00000000_00000000_11000011_01000010;
-                let stones_store = ((CORNER & stones).count_ones() << 5) as i32 - ((NEAR_CORNER & stones).count_ones() << 3) as i32;
-                stones_store * 8 + mvs.count_ones() as i32 * 4
+    /// 局面 (石の配置と手番) のZobristハッシュ値
+    pub fn hash(&self) -> u64 {
+        self.hash
+    }
+
+    /// 手番側の本当の色
+    pub fn side(&self) -> Color {
+        self.side
+    }
+
+    /// 手番側の石
+    pub fn player(&self) -> u64 {
+        self.player
+    }
+
+    /// 手番でない側の石
+    pub fn opponent(&self) -> u64 {
+        self.opponent
+    }
+
+    /// 黒石 (X)
+    pub fn black(&self) -> u64 {
+        if self.side == Color::White { self.opponent } else { self.player }
+    }
+
+    /// 白石 (O)
+    pub fn white(&self) -> u64 {
+        if self.side == Color::White { self.player } else { self.opponent }
+    }
+
+    /// 盤面を本当の色で表示する (X: 黒, O: 白)
+    #[allow(dead_code)]
+    pub fn print(&self) {
+        print!("{}", self.diagram());
+    }
+
+    /// `print`で表示する図 (`FromStr`で読み戻せる)
+    pub fn diagram(&self) -> String {
+        let mut s = String::from(" |A B C D E F G H\n-+---------------\n");
+        for row in 1..=8 {
+            s += &format!("{}|", row);
+            for col in 1..=8 {
+                s.push(self.square_char(move_to_idx(&Move::To(col, row))));
+                s.push(' ');
             }
-            eval(self.black, black_mvs) - eval(self.white, white_mvs)
+            s.push('\n');
         }
-        
-            
-            // we use evaluator fun here, this one is high performance but low speed
-            // evaluator::evaluate_board(self, black_mvs, white_mvs)
+        s += &format!("{} to move\n", side_char(self.side));
+        s
     }
 
-    pub fn evaluate_end(&self) -> i32 {
-        if self.black.count_ones() > self.white.count_ones() {
-            Board::MAX_EVAL
-        } else if self.black.count_ones() < self.white.count_ones() {
-            -Board::MAX_EVAL
+    // マスの石を表す文字 (X: 黒, O: 白, -: 空き)
+    fn square_char(&self, mask: u64) -> char {
+        if self.black() & mask != 0 {
+            'X'
+        } else if self.white() & mask != 0 {
+            'O'
         } else {
-            0
+            '-'
         }
     }
 
-    pub fn get_black(&self) -> u64 {
-        self.black
+    pub fn exchange(&mut self) {
+        let tmp = self.opponent.clone();
+        self.opponent = self.player.clone();
+        self.player = tmp;
+        self.side = if self.side == Color::White { Color::Black } else { Color::White };
+        self.hash ^= ZOBRIST_SIDE;
     }
 
-    pub fn get_white(&self) -> u64 {
-        self.white
+    /// 終局した局面の評価値 (勝ち・負けは石差付きの読み切った値)
+    pub fn evaluate_end(&self) -> Score {
+        score::proven(self.player.count_ones() as i32 - self.opponent.count_ones() as i32)
     }
 
     /// 現在の盤面状態を指定のフォーマットで表示する
-    pub fn print_board(&self, my_color: crate::cmds::Color) {
-        println!(" |A B C D E F G H ");
-        println!("-+----------------");
-        for y in 0..8 {
-            print!("{}|", y + 1);
-            for x in 0..8 {
-                let pos = 1u64 << (y * 8 + x);
-                let stone = if (self.black & pos) != 0 {
-                    'X' // Black
-                } else if (self.white & pos) != 0 {
-                    'O' // White
-                } else {
-                    ' ' // Empty
-                };
-                print!(" {}", stone);
-            }
-            println!(" ");
+    pub fn print_board(&self, my_color: Color) {
+        self.print();
+        match my_color {
+            Color::Black => println!("  (X: Black (me),  O: White)"),
+            Color::White => println!("  (X: Black,  O: White (me))"),
+            Color::Empty => println!("  (X: Black,  O: White)"),
         }
-        println!("  (X: Black,  O: White)");
     }
 
     pub fn legals(&self) -> (u64, [(u64, u64); 4]) {
-        let blank: u64 = !(self.black.clone() | self.white.clone());
+        let blank: u64 = !(self.player.clone() | self.opponent.clone());
         let mut legals: u64 = 0;
         let mut hints: [(u64, u64); 4] = [(0, 0); 4];
 
@@ -163,7 +252,7 @@ impl Board {
 
         for i in 0..4 {
             let idx = i as usize;
-            hints[idx] = calc_legal(&self.white, &self.black, &Board::MASKS[idx].0, &Board::MASKS[idx].1);
+            hints[idx] = calc_legal(&self.opponent, &self.player, &Board::MASKS[idx].0, &Board::MASKS[idx].1);
             hints[idx].0 &= blank;
             hints[idx].1 &= blank;
             legals |= hints[idx].0.clone();
@@ -175,6 +264,56 @@ impl Board {
 
     pub fn next(&mut self, mv: u64, hints: [(u64, u64); 4]) {
         debug_assert!(mv.count_ones() == 1);
+        let rev = self.flips(mv, hints);
+        self.toggle(mv, rev);
+        self.turns += 1;
+    }
+
+    /// mvを打って手番を相手に渡す。返り値をunmake_moveに渡すと元の局面に戻る
+    pub fn make_move(&mut self, mv: u64, hints: [(u64, u64); 4]) -> Undo {
+        debug_assert!(mv.count_ones() == 1);
+        let flipped = self.flips(mv, hints);
+        self.toggle(mv, flipped);
+        self.turns += 1;
+        self.exchange();
+        Undo { mv, flipped }
+    }
+
+    /// パスして手番を相手に渡す
+    pub fn make_pass(&mut self) -> Undo {
+        self.exchange();
+        Undo { mv: 0, flipped: 0 }
+    }
+
+    /// make_move・make_passで進めた局面を戻す
+    pub fn unmake_move(&mut self, undo: Undo) {
+        self.exchange();
+        if undo.mv != 0 {
+            self.toggle(undo.mv, undo.flipped);
+            self.turns -= 1;
+        }
+    }
+
+    // mvを置いてrevを裏返す。同じ引数でもう一度呼ぶと元に戻る
+    #[inline]
+    fn toggle(&mut self, mv: u64, rev: u64) {
+        self.player ^= mv | rev;
+        self.opponent ^= rev;
+
+        // 置いた石と裏返った石の分だけハッシュ値を更新する
+        let me = (self.side == Color::White) as usize;
+        self.hash ^= ZOBRIST[me][mv.trailing_zeros() as usize];
+        let mut flipped = rev;
+        while flipped != 0 {
+            let sq = flipped.trailing_zeros() as usize;
+            self.hash ^= ZOBRIST[0][sq] ^ ZOBRIST[1][sq];
+            flipped &= flipped - 1;
+        }
+        debug_assert_eq!(self.hash, Board::compute_hash(self.player, self.opponent, self.side));
+    }
+
+    // mvを打ったときに裏返る石
+    fn flips(&self, mv: u64, hints: [(u64, u64); 4]) -> u64 {
         #[inline]
         fn calc_rev(white: &u64, shift: &u8, mask: &u64, mv: &u64, hint: (u64, u64)) -> u64 {
             let mut rev0: u64 = 0;
@@ -201,12 +340,221 @@ impl Board {
         let mut rev: u64 = 0;
         for i in 0..4 {
             let idx = i as usize;
-            rev |= calc_rev(&self.white, &Board::MASKS[idx].0, &Board::MASKS[idx].1, &mv, hints[idx]);
+            rev |= calc_rev(&self.opponent, &Board::MASKS[idx].0, &Board::MASKS[idx].1, &mv, hints[idx]);
         }
+        rev
+    }
+}
 
-        self.black |= mv | rev;
-        self.white ^= rev;
-        self.turns += 1;
+fn side_char(side: Color) -> char {
+    if side == Color::White { 'O' } else { 'X' }
+}
+
+// マスの文字を色に変換する
+fn parse_square(c: char) -> Option<Color> {
+    match c {
+        'X' | 'x' | '*' => Some(Color::Black),
+        'O' | 'o' => Some(Color::White),
+        '-' | '.' | ' ' => Some(Color::Empty),
+        _ => None,
+    }
+}
+
+// 手番を表す文字列 ("X", "O", "black", "white") を色に変換する
+fn parse_side(s: &str) -> Option<Color> {
+    match s.to_ascii_lowercase().as_str() {
+        "x" | "*" | "black" => Some(Color::Black),
+        "o" | "white" => Some(Color::White),
+        _ => None,
+    }
+}
+
+/// A1, B1, ..., H1, A2, ..., H8 の順に64マスを並べ、空白を挟んで手番を続けた形式
+/// (例: 初期盤面は `---------------------------OX------XO--------------------------- X`)
+/// OBFやFFOのテスト局面と同じ形式
+impl fmt::Display for Board {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for row in 1..=8 {
+            for col in 1..=8 {
+                write!(f, "{}", self.square_char(move_to_idx(&Move::To(col, row))))?;
+            }
+        }
+        write!(f, " {}", side_char(self.side))
+    }
+}
+
+/// `Display`の一行形式と、`print`で表示する図の形式のどちらでも読める
+/// 一行形式では`;`以降 (OBFの評価値など) を無視する。図に手番の行がなければ黒番とする
+impl FromStr for Board {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Board, String> {
+        let (mut black, mut white) = (0, 0);
+        let mut put_square = |i: usize, c: char| -> Result<(), String> {
+            let mask = move_to_idx(&Move::To((i % 8 + 1) as i32, (i / 8 + 1) as i32));
+            match parse_square(c) {
+                Some(Color::Black) => black |= mask,
+                Some(Color::White) => white |= mask,
+                Some(Color::Empty) => {}
+                None => return Err(format!("invalid square '{}'", c)),
+            }
+            Ok(())
+        };
+
+        let side = if s.trim().contains('\n') {
+            // 図の形式: "1|X O ..." の行と "X to move" の行を読む
+            let mut rows = [false; 8];
+            let mut side = Color::Black;
+            for line in s.lines() {
+                let line = line.trim_start();
+                let mut chars = line.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(r @ '1'..='8'), Some('|')) => {
+                        let row = r as usize - '1' as usize;
+                        // 同じ行が二度あると石が重なる
+                        if rows[row] {
+                            return Err(format!("row {} is given twice", r));
+                        }
+                        rows[row] = true;
+                        let cells: Vec<char> = chars.collect();
+                        for col in 0..8 {
+                            let c = cells.get(col * 2).ok_or(format!("row {} has fewer than 8 squares", r))?;
+                            put_square(row * 8 + col, *c)?;
+                        }
+                    }
+                    _ if line.ends_with("to move") => {
+                        let name = line.split_whitespace().next().unwrap_or("");
+                        side = parse_side(name).ok_or(format!("invalid side to move '{}'", name))?;
+                    }
+                    _ => {}
+                }
+            }
+            let found = rows.iter().filter(|&&r| r).count();
+            if found != 8 {
+                return Err(format!("expected 8 rows, found {}", found));
+            }
+            side
+        } else {
+            // 一行形式
+            let s = s.split(';').next().unwrap_or("");
+            let squares: Vec<char> = s.chars().filter(|c| !c.is_whitespace()).collect();
+            if squares.len() != 65 {
+                return Err(format!("expected 64 squares and a side to move, found {} characters", squares.len()));
+            }
+            for (i, &c) in squares[..64].iter().enumerate() {
+                put_square(i, c)?;
+            }
+            parse_side(&squares[64].to_string()).ok_or(format!("invalid side to move '{}'", squares[64]))?
+        };
+        Ok(Board::from_discs(black, white, side))
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    fn assert_hash(board: &Board) {
+        assert_eq!(board.hash(), Board::compute_hash(board.player, board.opponent, board.side));
+    }
+
+    // 初期局面からf5に打った白番の局面
+    fn after_f5() -> Board {
+        let mut board = Board::new();
+        let (_, hints) = board.legals();
+        board.make_move(move_to_idx(&Move::To(6, 5)), hints);
+        board
+    }
+
+    #[test]
+    fn position_string_round_trip() {
+        let start = Board::new();
+        assert_eq!(start.to_string(), "---------------------------OX------XO--------------------------- X");
+        let white = after_f5();
+        assert_eq!(white.to_string(), "---------------------------OX------XXX-------------------------- O");
+        for board in [start, white] {
+            let parsed: Board = board.to_string().parse().unwrap();
+            assert_eq!(parsed, board);
+        }
+        // 小文字や別の記号、空白と`;`以降も読める
+        let loose: Board = "---------------------------ox------xxx.......................... o ; comment".parse().unwrap();
+        assert_eq!(loose, after_f5());
+    }
+
+    #[test]
+    fn diagram_round_trip() {
+        for board in [Board::new(), after_f5()] {
+            let parsed: Board = board.diagram().parse().unwrap();
+            assert_eq!(parsed, board);
+        }
+        // 手番の行がなければ黒番
+        let without_side: String = Board::new().diagram().lines().take(10).map(|l| format!("{}\n", l)).collect();
+        assert_eq!(without_side.parse::<Board>().unwrap(), Board::new());
+    }
+
+    #[test]
+    fn position_string_rejects_invalid_input() {
+        let squares = Board::new().to_string()[..64].to_string();
+        assert!(format!("{} X", &squares[..63]).parse::<Board>().is_err());
+        assert!(format!("{}- X", squares).parse::<Board>().is_err());
+        assert!(format!("Z{} X", &squares[1..]).parse::<Board>().is_err());
+        assert!(squares.parse::<Board>().is_err());
+        assert!(format!("{} Z", squares).parse::<Board>().is_err());
+        assert!(format!("あ{} X", &squares[1..]).parse::<Board>().is_err());
+    }
+
+    #[test]
+    fn diagram_rejects_invalid_input() {
+        let diagram = after_f5().diagram();
+        let lines: Vec<&str> = diagram.lines().collect();
+        let join = |lines: &[&str]| lines.iter().map(|l| format!("{}\n", l)).collect::<String>();
+        // 手番が読めない
+        assert!(diagram.replace("O to move", "Z to move").parse::<Board>().is_err());
+        // 行が足りない・短い・文字が違う
+        assert!(join(&[&lines[..9], &lines[10..]].concat()).parse::<Board>().is_err());
+        assert!(diagram.replace("5|- - - X X X - - ", "5|- - - X X X").parse::<Board>().unwrap_err().contains("fewer than 8"));
+        assert!(diagram.replace("4|- - - O X - - - ", "4|- - - O Z - - - ").parse::<Board>().is_err());
+        // 同じ行が二度あると、4段目の石と5段目の石が重なる
+        let mut repeated = lines.clone();
+        repeated[6] = "4|- - - O X - - - ";
+        assert_eq!(join(&repeated).parse::<Board>().unwrap_err(), "row 4 is given twice");
+    }
+
+    #[test]
+    fn incremental_hash_matches_recomputed_hash() {
+        let mut rng = StdRng::seed_from_u64(1);
+        for _ in 0..200 {
+            let mut board = Board::new();
+            let mut history = vec![(board.clone(), None)];
+            loop {
+                let (legals, hints) = board.legals();
+                let undo = if legals != 0 {
+                    // 合法手の中から一つを選ぶ
+                    let mut bits = legals;
+                    for _ in 0..rng.gen_range(0..legals.count_ones()) {
+                        bits &= bits - 1;
+                    }
+                    board.make_move(bits & bits.wrapping_neg(), hints)
+                } else {
+                    let undo = board.make_pass();
+                    if board.legals().0 == 0 {
+                        // どちらも打てないので終局
+                        board.unmake_move(undo);
+                        break;
+                    }
+                    undo
+                };
+                assert_hash(&board);
+                history.push((board.clone(), Some(undo)));
+            }
+            // 一手ずつ戻し、打つ前の局面 (ハッシュ値を含む) に戻ることを確かめる
+            while let Some((_, Some(undo))) = history.pop() {
+                board.unmake_move(undo);
+                assert_hash(&board);
+                assert_eq!(board, history.last().unwrap().0);
+            }
+        }
+    }
+}
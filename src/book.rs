@@ -1,8 +1,14 @@
-use once_cell::sync::Lazy;
-use std::collections::HashMap;
-use crate::bitboard::Board;
+use once_cell::sync::OnceCell;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::path::Path;
 
-// ユーザーから提供された定石データを文字列定数として埋め込む
+use crate::bitboard::{Board, BuildZobristHasher};
+use crate::cmds::{idx_to_move, move_to_idx, move_to_string, string_to_move, Move};
+use crate::game::Game;
+
+// ユーザーから提供された定石データを文字列定数として埋め込む (`--book`を指定しなければこれを使う)
 const BOOK_DATA: &'static str = r#"
 f5
 f5d6
@@ -85,10 +91,6 @@ f5d6c4d3c3f4f6f3e6e7f7c5b6g5e3d7c6e2g4
 f5d6c4d3c3f4f6f3e6e7f7c5b6g5e3d7c6e2g4h3d2
 f5d6c4d3c3f4f6f3e6e7f7c5b6g5e3d7c6e2g4h3d2g3f1
 f5d6c4d3c3f4f6g6e3
-f5d6c4d3c3f4f6g6e3e2f1
-f5d6c4d3c3f4f6g6e3e2f1d1g5
-f5d6c4d3c3f4f6g6e3e2f1d1g5c6d8
-f5d6c4d3c3f4f6g6e3e2f1d1g5c6d8g4h6
 f5d6c4d3c3f4f6b4c2
 f5d6c4d3c3f4f6b4c2f3e3
 f5d6c4d3c3f4f6b4c2f3e3e2c6
@@ -170,80 +172,446 @@ f5f6e6d6f7f4d7e7d8g5c6f8g6h5h6h7c4e8g8c5e3
 f5f6e6d6f7f4d7e7d8g5c6f8g6h5h6h7c4e8g8c5e3d3c7
 "#;
 
-// Lazy<T> を使って、最初にアクセスされたときに一度だけBookを初期化する
-// これにより、プログラム全体で共有されるグローバルな定石DBが実現される
-pub static OPENING_BOOK: Lazy<Book> = Lazy::new(|| Book::new(BOOK_DATA));
+// バイナリ形式の定石ファイルの先頭 (続く1バイトが版番号)
+// その後に手順ごとに 手数 (1バイト), 各手のマス (1バイト、ビットの位置0〜63、パスはPASS) が並ぶ
+const MAGIC: &[u8] = b"RVBK";
+const VERSION: u8 = 1;
+const PASS: u8 = 64;
+
+// 探索で使う定石。最初に引いたときに決まり、それ以降は変わらない
+static OPENING_BOOK: OnceCell<Book> = OnceCell::new();
+
+/// 探索で使う定石。`load_opening_book`で読み込んでいなければ埋め込みの定石を使う
+pub fn opening_book() -> &'static Book {
+    OPENING_BOOK.get_or_init(Book::embedded)
+}
+
+/// `--book`で指定したファイルを探索で使う定石にする (定石を引く前に呼ぶこと)
+pub fn load_opening_book(path: &Path) -> Result<(), String> {
+    let book = Book::load(path)?;
+    eprintln!("[Info] Opening book {}: {} lines, {} positions", path.display(), book.lines.len(), book.map.len());
+    OPENING_BOOK.set(book).map_err(|_| "the opening book is already in use".to_string())
+}
+
+/// 定石の一手順 (初期局面からの手、0はパス)
+pub type Line = Vec<u64>;
 
 // 定石データベースを表す構造体
-// キー: (自分の石のビットボード, 相手の石のビットボード)
-// 値: 次に指すべき手を表すビットボード (u64)
+// キー: 標準形 (Board::canonical) に直した局面のZobristハッシュ値
+// 対称な局面は同じキーになるので、定石の手も標準形での手として持つ
+// 値: 標準形の局面 (手番側の石, 相手の石) と、次に指すべき手を表すビットボード (u64)
+// ハッシュ値が衝突した別の局面で定石の手を打たないよう、引くときに局面も比べる
 pub struct Book {
-    map: HashMap<(u64, u64), u64>,
+    map: HashMap<u64, (Position, u64), BuildZobristHasher>,
+    lines: Vec<Line>,
 }
 
 impl Book {
-    // 定石文字列から新しいBookインスタンスを作成する
-    pub fn new(book_data: &'static str) -> Self {
-        let mut map = HashMap::new();
-
-        for line in book_data.lines() {
-            let line = line.trim();
-            // 空行やコメントは無視
-            if line.is_empty() || line.starts_with('#') {
-                continue;
-            }
-
-            let mut board = Board::new();
-            
-            // 2文字ずつ手としてパースしていく (例: "f5d6c3...")
-            for i in (0..line.len()).step_by(2) {
-                if i + 2 > line.len() { continue; }
-                let move_str = &line[i..i + 2];
-                
-                // 現在の盤面をキーとして保存
-                let key = (board.get_black(), board.get_white());
-
-                // 文字列から座標に変換し、さらにビットボードでの表現に変換
-                let move_bit = move_str_to_bit(move_str);
-
-                // まだ登録されていなければ、現在の局面と次の手を登録
-                if !map.contains_key(&key) {
-                    map.insert(key, move_bit);
+    /// 手順の列から作る。同じ局面が何度も出てくるときは、最初の手順の手を使う
+    /// 手順はどれも合法であること (`BookFile`の読めた手順)
+    pub fn from_lines(lines: Vec<Line>) -> Self {
+        let mut map = HashMap::default();
+        for line in lines.iter() {
+            let mut game = Game::new();
+            for &mv in line.iter() {
+                if mv != 0 {
+                    let (canonical, sym) = game.board().canonical();
+                    map.entry(canonical.hash()).or_insert((position(&canonical), sym.apply(mv)));
                 }
+                game.play(mv).expect("opening book line is not legal");
+            }
+        }
+        Book { map, lines }
+    }
 
-                // 実際に手を打って盤面を進める
-                let (legals, hints) = board.legals();
+    /// 埋め込みの定石。読めない手順は警告して使わない
+    pub fn embedded() -> Self {
+        let file = parse_text(BOOK_DATA);
+        for invalid in file.invalid.iter() {
+            eprintln!("[Warn] built-in opening book:{}", invalid);
+        }
+        Book::from_lines(file.lines)
+    }
 
-                if (legals & move_bit) != 0 {
-                    board.next(move_bit, hints);
-                    board.exchange(); // 手番を交代
-                } else {
-                    // 定石データに不正な手が含まれている場合は警告を出して中断
-                    // eprintln!("Invalid move '{}' in opening book for line: {}", move_str, line);
-                    break;
-                }
+    /// 定石ファイル (テキスト形式かバイナリ形式) を読む
+    /// 読めない手順があればすべて表示してエラーにし、同じ局面に違う手を書いた手順があれば警告する
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let file = BookFile::read(path)?;
+        if !file.invalid.is_empty() {
+            for invalid in file.invalid.iter() {
+                eprintln!("{}:{}", path.display(), invalid);
             }
+            return Err(format!("{}: {} invalid lines (run `book check` for details)", path.display(), file.invalid.len()));
+        }
+        let conflicts = check(&file).conflicts.len();
+        if conflicts > 0 {
+            eprintln!("[Warn] {}: {} positions have different moves in different lines; the first one is used", path.display(), conflicts);
         }
-        Book { map }
+        Ok(Book::from_lines(file.lines))
     }
 
-    // 現在の盤面(board)に一致する定石手があれば返す
+    /// 定石の手順 (ファイルの順)
+    pub fn lines(&self) -> &[Line] {
+        &self.lines
+    }
+
+    // 現在の盤面(board)か、それと対称な盤面に一致する定石手があれば返す
     pub fn get(&self, board: &Board) -> Option<u64> {
-        let key = (board.get_black(), board.get_white());
-        self.map.get(&key).cloned()
+        let (canonical, sym) = board.canonical();
+        let &(pos, mv) = self.map.get(&canonical.hash())?;
+        if pos != position(&canonical) {
+            return None;
+        }
+        Some(sym.inverse().apply(mv))
     }
 }
 
-// "f5" のような文字列を u64 のビットボードに変換する
-fn move_str_to_bit(s: &str) -> u64 {
-    let mut chars = s.chars();
-    let col = match chars.next() {
-        Some(c) => c as u8 - b'a',
-        None => return 0,
+/// 読めない手順。その手を打とうとした局面も持つ
+pub struct InvalidLine {
+    /// 行番号 (バイナリ形式では何番目の手順か)
+    pub number: usize,
+    /// 何手目か (1から)
+    pub index: usize,
+    pub message: String,
+    pub board: Board,
+}
+
+impl fmt::Display for InvalidLine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {} (move {}) at {}", self.number, self.message, self.index, self.board)
+    }
+}
+
+/// 定石ファイルを読んだ結果。読めた手順と読めなかった手順に分ける
+pub struct BookFile {
+    pub lines: Vec<Line>,
+    /// linesの各手順の行番号
+    pub numbers: Vec<usize>,
+    pub invalid: Vec<InvalidLine>,
+}
+
+impl BookFile {
+    /// 先頭がMAGICならバイナリ形式、そうでなければテキスト形式として読む
+    /// バイナリ形式のヘッダや手順の長さが壊れていて先を読めないときだけエラーにする
+    pub fn read(path: &Path) -> Result<BookFile, String> {
+        let bytes = fs::read(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        if bytes.starts_with(MAGIC) {
+            return parse_binary(&bytes).map_err(|e| format!("{}: {}", path.display(), e));
+        }
+        let text = String::from_utf8(bytes).map_err(|_| format!("{}: neither a text nor a binary opening book", path.display()))?;
+        Ok(parse_text(&text))
+    }
+
+    fn push(&mut self, number: usize, line: Result<Line, InvalidLine>) {
+        match line {
+            Ok(line) if line.is_empty() => {}
+            Ok(line) => {
+                self.lines.push(line);
+                self.numbers.push(number);
+            }
+            Err(invalid) => self.invalid.push(InvalidLine { number, ..invalid }),
+        }
+    }
+}
+
+// movesの前のindex手目 (1から) が読めないか打てないときのエラー
+fn invalid_move(moves: &[u64], index: usize, message: String) -> InvalidLine {
+    let mut game = Game::new();
+    for &mv in moves {
+        game.play(mv).expect("opening book line is not legal");
+    }
+    InvalidLine { number: 0, index, message, board: game.board().clone() }
+}
+
+// 一行の手順を読む。"f5d6c3" のように2文字ずつ手を並べる (大文字でもよく、空白と`#`以降は無視する)
+// 手番側に合法手がなければパスを補う
+fn parse_line(text: &str) -> Result<Line, InvalidLine> {
+    let chars: Vec<char> = text.split('#').next().unwrap_or("").chars().filter(|c| !c.is_whitespace()).collect();
+    let mut game = Game::new();
+    for (i, token) in chars.chunks(2).enumerate() {
+        let token: String = token.iter().collect();
+        let mv = match string_to_move(&token) {
+            Some(mv @ Move::To(_, _)) => mv,
+            _ => return Err(invalid_move(&game.moves(), i + 1, format!("invalid move '{}'", token))),
+        };
+        if game.board().legals().0 == 0 && !game.is_over() {
+            game.play(0).expect("pass without legal moves");
+        }
+        if game.play(move_to_idx(&mv)).is_err() {
+            return Err(invalid_move(&game.moves(), i + 1, format!("illegal move {}", token)));
+        }
+    }
+    Ok(game.moves())
+}
+
+// テキスト形式 (一行一手順、空行と`#`で始まる行は無視) を読む
+fn parse_text(text: &str) -> BookFile {
+    let mut file = BookFile { lines: Vec::new(), numbers: Vec::new(), invalid: Vec::new() };
+    for (i, text) in text.lines().enumerate() {
+        file.push(i + 1, parse_line(text));
+    }
+    file
+}
+
+// バイナリ形式の一手順を読む
+fn parse_record(squares: &[u8]) -> Result<Line, InvalidLine> {
+    let mut game = Game::new();
+    for (i, &square) in squares.iter().enumerate() {
+        let mv = match square {
+            PASS => 0,
+            0..=63 => 1u64 << square,
+            _ => return Err(invalid_move(&game.moves(), i + 1, format!("invalid square {}", square))),
+        };
+        if game.play(mv).is_err() {
+            return Err(invalid_move(&game.moves(), i + 1, format!("illegal move {}", move_to_string(idx_to_move(&mv)))));
+        }
+    }
+    Ok(game.moves())
+}
+
+// バイナリ形式を読む
+fn parse_binary(bytes: &[u8]) -> Result<BookFile, String> {
+    let body = &bytes[MAGIC.len()..];
+    match body.first() {
+        Some(&VERSION) => {}
+        Some(v) => return Err(format!("unsupported binary book version {} (expected {})", v, VERSION)),
+        None => return Err("truncated header".to_string()),
+    }
+    let mut rest = &body[1..];
+    let mut file = BookFile { lines: Vec::new(), numbers: Vec::new(), invalid: Vec::new() };
+    let mut record = 0;
+    while let Some((&len, tail)) = rest.split_first() {
+        record += 1;
+        if tail.len() < len as usize {
+            return Err(format!("record {}: truncated ({} of {} moves)", record, tail.len(), len));
+        }
+        let (squares, tail) = tail.split_at(len as usize);
+        file.push(record, parse_record(squares));
+        rest = tail;
+    }
+    Ok(file)
+}
+
+/// 同じ局面に、先の手順と違う手を書いた手順
+pub struct Conflict {
+    pub number: usize,
+    /// 何手目か (1から)
+    pub index: usize,
+    pub board: Board,
+    pub mv: u64,
+    /// 先にこの局面の手を決めた手順の行番号とその手 (定石ではこちらを使う)
+    pub first: usize,
+    pub first_mv: u64,
+}
+
+/// 定石の手順どうしの重複・食い違いと、手数ごとの局面の数
+pub struct Report {
+    /// (行番号, 同じ手順 (対称なものを含む) が先に出てきた行番号)
+    pub duplicates: Vec<(usize, usize)>,
+    pub conflicts: Vec<Conflict>,
+    /// 手数 (パスを含む) ごとの、定石の手がある局面の数 (対称な局面は一つと数える)
+    pub positions_per_ply: Vec<usize>,
+}
+
+// 局面 (手番側の石, 相手の石)
+type Position = (u64, u64);
+
+fn position(board: &Board) -> Position {
+    (board.player(), board.opponent())
+}
+
+/// 読めた手順どうしを比べる。局面はどれも標準形に直して比べ、手は打った後の局面で比べる
+/// (初期局面のように対称な局面では、f5とc4のように違うマスでも同じ手になる)
+pub fn check(file: &BookFile) -> Report {
+    let mut report = Report { duplicates: Vec::new(), conflicts: Vec::new(), positions_per_ply: Vec::new() };
+    // 標準形の局面 -> (手を打った後の標準形の局面, 標準形での手, 最初の行番号)
+    // 局面はハッシュ値ではなく石の配置で比べるので、衝突で違う局面を同じとみなすことはない
+    let mut moves: HashMap<Position, (Position, u64, usize)> = HashMap::new();
+    let mut reported: HashSet<(Position, Position)> = HashSet::new();
+    let mut sequences: HashMap<Vec<Position>, usize> = HashMap::new();
+    let mut per_ply: Vec<HashSet<Position>> = Vec::new();
+    for (line, &number) in file.lines.iter().zip(file.numbers.iter()) {
+        let mut game = Game::new();
+        let mut sequence = Vec::new();
+        for (ply, &mv) in line.iter().enumerate() {
+            let (canonical, sym) = game.board().canonical();
+            let key = position(&canonical);
+            let board = game.board().clone();
+            game.play(mv).expect("opening book line is not legal");
+            let next = position(&game.board().canonical().0);
+            sequence.push(next);
+            if mv == 0 {
+                continue;
+            }
+            if per_ply.len() <= ply {
+                per_ply.resize_with(ply + 1, HashSet::new);
+            }
+            per_ply[ply].insert(key);
+            let &mut (first_next, first_mv, first) = moves.entry(key).or_insert((next, sym.apply(mv), number));
+            if first_next != next && reported.insert((key, next)) {
+                report.conflicts.push(Conflict { number, index: ply + 1, board, mv, first, first_mv: sym.inverse().apply(first_mv) });
+            }
+        }
+        match sequences.get(&sequence) {
+            Some(&first) => report.duplicates.push((number, first)),
+            None => {
+                sequences.insert(sequence, number);
+            }
+        }
+    }
+    report.positions_per_ply = per_ply.iter().map(|positions| positions.len()).collect();
+    report
+}
+
+/// `book check`: 定石ファイル (pathがNoneなら埋め込みの定石) の問題と、手数ごとの局面の数を表示する
+pub fn print_check(path: Option<&Path>) -> Result<(), String> {
+    let (name, file) = match path {
+        Some(path) => (path.display().to_string(), BookFile::read(path)?),
+        None => ("built-in book".to_string(), parse_text(BOOK_DATA)),
     };
-    let row = match chars.next() {
-        Some(c) => c as u8 - b'1',
-        None => return 0,
+    let report = check(&file);
+    let positions: usize = report.positions_per_ply.iter().sum();
+    let max_depth = file.lines.iter().map(|line| line.len()).max().unwrap_or(0);
+    println!("{}: {} lines ({} invalid), {} positions, max depth {}", name, file.lines.len() + file.invalid.len(), file.invalid.len(), positions, max_depth);
+
+    let show = |mv: u64| move_to_string(idx_to_move(&mv));
+    for invalid in file.invalid.iter() {
+        println!("\nline {}: {} (move {})", invalid.number, invalid.message, invalid.index);
+        invalid.board.print();
+    }
+    for &(number, first) in report.duplicates.iter() {
+        println!("\nline {}: same as line {} (up to symmetry)", number, first);
+    }
+    for conflict in report.conflicts.iter() {
+        println!(
+            "\nline {}: move {} is {}, but line {} plays {} here (the first one is used)",
+            conflict.number, conflict.index, show(conflict.mv), conflict.first, show(conflict.first_mv)
+        );
+        conflict.board.print();
+    }
+
+    println!("\nmove positions");
+    for (ply, count) in report.positions_per_ply.iter().enumerate() {
+        println!("{:>4} {:>9}", ply + 1, count);
+    }
+    // 食い違いは分岐した変化として書かれていることが多いので、読めない手順や重複とは分けて数える
+    println!(
+        "\n{} invalid lines, {} duplicate lines, {} conflicting positions",
+        file.invalid.len(),
+        report.duplicates.len(),
+        report.conflicts.len()
+    );
+    Ok(())
+}
+
+/// 手順をファイルに書き出す。binaryならバイナリ形式、そうでなければテキスト形式 (パスは書かない)
+pub fn write_lines(path: &Path, lines: &[Line], binary: bool) -> Result<(), String> {
+    let bytes = if binary {
+        let mut bytes = MAGIC.to_vec();
+        bytes.push(VERSION);
+        for line in lines {
+            bytes.push(line.len() as u8);
+            bytes.extend(line.iter().map(|&mv| if mv == 0 { PASS } else { mv.trailing_zeros() as u8 }));
+        }
+        bytes
+    } else {
+        let mut text = String::new();
+        for line in lines {
+            let moves: Vec<String> =
+                line.iter().filter(|&&mv| mv != 0).map(|mv| move_to_string(idx_to_move(mv)).to_ascii_lowercase()).collect();
+            text += &moves.concat();
+            text.push('\n');
+        }
+        text.into_bytes()
     };
-    1u64 << (row * 8 + col)
+    fs::write(path, bytes).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mv(s: &str) -> u64 {
+        move_to_idx(&string_to_move(s).unwrap())
+    }
+
+    // write_linesで書いてBookFile::readで読み直す
+    fn round_trip(lines: &[Line], binary: bool) -> BookFile {
+        let path = std::env::temp_dir().join(format!("reversi-book-test-{}-{}", std::process::id(), binary));
+        write_lines(&path, lines, binary).unwrap();
+        let file = BookFile::read(&path);
+        fs::remove_file(&path).unwrap();
+        file.unwrap()
+    }
+
+    #[test]
+    fn text_and_binary_round_trip() {
+        let lines = parse_text(BOOK_DATA).lines;
+        assert!(!lines.is_empty());
+        for binary in [false, true] {
+            let file = round_trip(&lines, binary);
+            assert!(file.invalid.is_empty());
+            assert_eq!(file.lines, lines);
+            assert_eq!(file.numbers, (1..=lines.len()).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn text_skips_comments_and_blank_lines() {
+        let file = parse_text("# comment\n\nF5 D6 # f5d6\nf5d6c3\n");
+        assert_eq!(file.lines, vec![vec![mv("f5"), mv("d6")], vec![mv("f5"), mv("d6"), mv("c3")]]);
+        assert_eq!(file.numbers, vec![3, 4]);
+        assert!(file.invalid.is_empty());
+    }
+
+    #[test]
+    fn text_rejects_invalid_and_illegal_moves() {
+        let file = parse_text("f5d6\nf5x9\na1\nf5d\nf5あ\n");
+        assert_eq!(file.lines.len(), 1);
+        let invalid: Vec<(usize, usize)> = file.invalid.iter().map(|i| (i.number, i.index)).collect();
+        assert_eq!(invalid, vec![(2, 2), (3, 1), (4, 2), (5, 2)]);
+        assert_eq!(file.invalid[1].message, "illegal move a1");
+        // 読めなかった手を打とうとした局面を持つ
+        assert!(file.invalid[0].board == *Game::from_transcript("f5").unwrap().board());
+    }
+
+    #[test]
+    fn binary_rejects_broken_files() {
+        let header = [MAGIC, &[VERSION]].concat();
+        assert!(parse_binary(MAGIC).is_err());
+        assert!(parse_binary(&[MAGIC, &[VERSION + 1]].concat()).is_err());
+        // 手数より手が少ない
+        assert!(parse_binary(&[&header[..], &[2, 37]].concat()).is_err());
+        // 盤外のマスと打てない手は読めない手順として数え、残りは読む
+        let f5 = mv("f5").trailing_zeros() as u8;
+        let a1 = mv("a1").trailing_zeros() as u8;
+        let file = parse_binary(&[&header[..], &[1, 70, 1, a1, 1, f5]].concat()).unwrap();
+        assert_eq!(file.lines, vec![vec![mv("f5")]]);
+        assert_eq!(file.numbers, vec![3]);
+        let invalid: Vec<(usize, &str)> = file.invalid.iter().map(|i| (i.number, i.message.as_str())).collect();
+        assert_eq!(invalid, vec![(1, "invalid square 70"), (2, "illegal move A1")]);
+    }
+
+    #[test]
+    fn get_checks_the_position_behind_the_hash() {
+        let mut book = Book::from_lines(vec![vec![mv("f5")]]);
+        let start = Board::new();
+        assert!(book.get(&start).is_some_and(|m| m & start.legals().0 != 0));
+        // 同じハッシュ値に別の局面が入っていれば (衝突)、定石の手を返さない
+        let key = start.canonical().0.hash();
+        book.map.get_mut(&key).unwrap().0 = (0, 0);
+        assert_eq!(book.get(&start), None);
+    }
+
+    #[test]
+    fn check_finds_duplicates_and_conflicts_up_to_symmetry() {
+        // c4e3はf5d6を180度回したもの。f5とc4は初期局面では同じ手なので食い違いではない
+        let report = check(&parse_text("f5d6\nc4e3\nf5\nc4\nf5f6\n"));
+        assert_eq!(report.duplicates, vec![(2, 1), (4, 3)]);
+        assert_eq!(report.conflicts.len(), 1);
+        let conflict = &report.conflicts[0];
+        assert_eq!((conflict.number, conflict.index, conflict.first), (5, 2, 1));
+        assert_eq!((conflict.mv, conflict.first_mv), (mv("f6"), mv("d6")));
+        assert_eq!(report.positions_per_ply, vec![1, 1]);
+    }
 }
@@ -31,6 +31,23 @@ pub fn move_to_string(m : Move) -> String {
     }
 }
 
+/// "F5", "f5", "PASS" のような文字列を手に変換する
+pub fn string_to_move(s: &str) -> Option<Move> {
+    if s.eq_ignore_ascii_case("PASS") {
+        return Some(Move::Pass);
+    }
+    let mut chars = s.chars();
+    let (c, r) = match (chars.next(), chars.next(), chars.next()) {
+        (Some(c), Some(r), None) => (c.to_ascii_uppercase(), r),
+        _ => return None,
+    };
+    if ('A'..='H').contains(&c) && ('1'..='8').contains(&r) {
+        Some(Move::To(c as i32 - 'A' as i32 + 1, r as i32 - '1' as i32 + 1))
+    } else {
+        None
+    }
+}
+
 pub fn idx_to_move(mv: &u64) -> Move {
     if *mv == 0 {
         Move::Pass
@@ -0,0 +1,161 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::bitboard::Board;
+use crate::cmds::Color;
+use crate::game::Game;
+
+/// 学習に使う局面と、その局面の手番側から見た最終的な石差
+pub struct Sample {
+    pub board: Board,
+    pub target: f32,
+}
+
+/// 読み込んだ学習データ
+pub struct Dataset {
+    pub samples: Vec<Sample>,
+    /// 局面を使った棋譜の数
+    pub games: usize,
+    /// 終局しておらず結果も書かれていないので使わなかった棋譜の位置 ("ファイル:行")
+    pub skipped: Vec<String>,
+}
+
+/// 学習用の局面を読み込む。ファイルには次の二つの形式の行を混ぜて書ける
+/// - `<局面の文字列 (Boardの一行形式)> <石差>` の一行一局面
+/// - 棋譜 (`--transcripts`で書き出したもの)。空行で区切った一局ごとに、終局時の石差を途中の全局面の目標にする
+///   石差は`# result: 黒の石数 - 白の石数`の見出しがあればそれを、なければ終局した最後の局面の石数を使う
+///   (ネットワーク対局の棋譜は最後の手が抜けることがあるため)。どちらもない棋譜は警告して使わない
+pub fn load(path: &Path) -> Result<Dataset, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    parse(&path.display().to_string(), &text)
+}
+
+// nameはメッセージに使うファイル名
+fn parse(name: &str, text: &str) -> Result<Dataset, String> {
+    let mut dataset = Dataset { samples: Vec::new(), games: 0, skipped: Vec::new() };
+    // 読みかけの棋譜 (開始行, 内容)
+    let mut transcript: Option<(usize, String)> = None;
+    for (i, line) in text.lines().chain(std::iter::once("")).enumerate() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.first().is_some_and(|f| f.len() == 64) {
+            let error = |msg: String| format!("{}:{}: {}", name, i + 1, msg);
+            if fields.len() != 3 {
+                return Err(error("expected '<position> <side> <disc difference>'".to_string()));
+            }
+            let board = format!("{} {}", fields[0], fields[1]).parse().map_err(error)?;
+            let target = fields[2].parse().map_err(|_| error(format!("invalid disc difference '{}'", fields[2])))?;
+            dataset.samples.push(Sample { board, target });
+        } else if fields.is_empty() {
+            if let Some((start, text)) = transcript.take() {
+                let game = Game::from_transcript(&text).map_err(|e| format!("{}:{}: {}", name, start, e))?;
+                match recorded_result(&text).or_else(|| game.is_over().then(|| game.score())) {
+                    Some((black, white)) => {
+                        dataset.samples.extend(game_samples(game, black as f32 - white as f32));
+                        dataset.games += 1;
+                    }
+                    None => {
+                        eprintln!("[Warn] {}:{}: the game is not over and has no result, skipped", name, start);
+                        dataset.skipped.push(format!("{}:{}", name, start));
+                    }
+                }
+            }
+        } else {
+            transcript.get_or_insert((i + 1, String::new())).1 += &format!("{}\n", line);
+        }
+    }
+    Ok(dataset)
+}
+
+// 棋譜の`# result:`の見出しから (黒の石数, 白の石数) を読む。"36 - 28" のように石数で始まるものだけを使う
+fn recorded_result(text: &str) -> Option<(u32, u32)> {
+    text.lines()
+        .filter_map(|line| line.strip_prefix('#')?.trim().strip_prefix("result:"))
+        .find_map(|result| {
+            let mut fields = result.split_whitespace();
+            let black = fields.next()?.parse().ok()?;
+            if fields.next()? != "-" {
+                return None;
+            }
+            let white = fields.next()?.parse().ok()?;
+            Some((black, white))
+        })
+}
+
+// 一局の途中の局面 (パスするしかない局面と最後の局面を除く) を黒から見た終局時の石差と組にする
+fn game_samples(mut game: Game, black_diff: f32) -> Vec<Sample> {
+    let mut samples = Vec::new();
+    while game.undo().is_some() {
+        let board = game.board();
+        if board.legals().0 != 0 {
+            let target = if board.side() == Color::Black { black_diff } else { -black_diff };
+            samples.push(Sample { board: board.clone(), target });
+        }
+    }
+    samples
+}
+
+/// 複数のファイルから読み込んでまとめる
+pub fn load_all(paths: &[PathBuf]) -> Result<Dataset, String> {
+    let mut dataset = Dataset { samples: Vec::new(), games: 0, skipped: Vec::new() };
+    for path in paths.iter() {
+        let mut more = load(path)?;
+        dataset.samples.append(&mut more.samples);
+        dataset.games += more.games;
+        dataset.skipped.append(&mut more.skipped);
+    }
+    Ok(dataset)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 白の手番で白には合法手がなく、黒がd1に打つと終局する局面
+    const PASS_POSITION: &str = "XOO------------------------------------------------------------- O";
+
+    #[test]
+    fn position_lines() {
+        let text = format!("{} +12\n{} -3.5\n", PASS_POSITION, Board::new());
+        let dataset = parse("test", &text).unwrap();
+        assert_eq!(dataset.samples.len(), 2);
+        assert_eq!(dataset.samples[0].target, 12.0);
+        assert_eq!(dataset.samples[1].target, -3.5);
+        assert!(dataset.samples[1].board == Board::new());
+    }
+
+    #[test]
+    fn rejects_invalid_lines() {
+        assert!(parse("test", &format!("{} 12 extra\n", PASS_POSITION)).is_err());
+        assert!(parse("test", &format!("{} twelve\n", PASS_POSITION)).is_err());
+        assert!(parse("test", "f5d6x9\n").is_err());
+    }
+
+    #[test]
+    fn finished_game_uses_final_board() {
+        let text = format!("# position: {}\nPASS D1\n", PASS_POSITION);
+        let dataset = parse("test", &text).unwrap();
+        assert_eq!(dataset.games, 1);
+        // パスするしかない最初の局面は使わず、黒の手番の局面に黒4-白0の石差を付ける
+        assert_eq!(dataset.samples.len(), 1);
+        assert_eq!(dataset.samples[0].board.side(), Color::Black);
+        assert_eq!(dataset.samples[0].target, 4.0);
+    }
+
+    #[test]
+    fn unfinished_game_uses_recorded_result_or_is_skipped() {
+        let dataset = parse("test", "# result: 40 - 24 (Win)\nf5d6c3\n\nf5d6c3\n").unwrap();
+        assert_eq!(dataset.games, 1);
+        assert_eq!(dataset.skipped, vec!["test:4".to_string()]);
+        let targets: Vec<(Color, f32)> = dataset.samples.iter().map(|s| (s.board.side(), s.target)).collect();
+        // 最後の局面から順に、f5d6の後 (黒), f5の後 (白), 初期局面 (黒)
+        assert_eq!(targets, vec![(Color::Black, 16.0), (Color::White, -16.0), (Color::Black, 16.0)]);
+    }
+
+    #[test]
+    fn recorded_result_overrides_final_board() {
+        let text = format!("# result: 10 - 54\n# position: {}\nPASS D1\n", PASS_POSITION);
+        let dataset = parse("test", &text).unwrap();
+        assert_eq!(dataset.samples[0].target, -44.0);
+        assert_eq!(recorded_result("# result: Lose (time up)\n"), None);
+    }
+}
@@ -0,0 +1,108 @@
+use clap::ValueEnum;
+use std::time::Duration;
+
+use crate::bitboard::Board;
+use crate::mcts::Mcts;
+use crate::score;
+use crate::search::{instant_move, search, SearchContext};
+
+/// 使用する探索エンジン
+#[derive(Clone, Copy, PartialEq, Eq, Debug, ValueEnum)]
+pub enum EngineKind {
+    /// Alpha-beta search
+    AlphaBeta,
+    /// Monte Carlo tree search (UCT)
+    Mcts,
+}
+
+// 一手あたりの思考時間の上限。残り時間が少ないほど短くする
+pub fn move_time_limit(time_level: usize) -> Duration {
+    match time_level {
+        0 => Duration::from_millis(8000),
+        1 => Duration::from_millis(4000),
+        _ => Duration::from_millis(1500),
+    }
+}
+
+// 速指しのときの探索の深さの上限
+const FAST_DEPTH: usize = 4;
+
+// 直前の評価値 (終局時の石差の予測) の差がこれ以上あるときは、勝敗が決まっているとみて思考時間を最短にする
+// `calibrate`で倍率を合わせた評価値が10石以上離れた局面は、約97%以上が評価値どおりの勝敗だった (READMEの評価値の単位)
+const DECIDED: score::Score = 10 * score::DISC;
+
+/// 探索エンジンとその状態 (乱数・MCTSの木など) をまとめたもの
+pub struct Engine {
+    pub kind: EngineKind,
+    pub depth: usize,
+    pub ctx: SearchContext,
+    pub mcts: Mcts,
+    /// 弱い相手に対して時間を節約する速指しモード
+    pub fast: bool,
+    /// 一手の思考時間に上限を設けるか
+    /// 上限で止まった探索は機械の速さや負荷で手が変わるので、同じ種で対局を再現したいときは外す
+    pub time_cap: bool,
+}
+
+impl Engine {
+    pub fn new(kind: EngineKind, depth: usize, seed: u64, mcts: Mcts) -> Engine {
+        Engine {
+            kind,
+            depth,
+            ctx: SearchContext::new(seed),
+            mcts,
+            fast: false,
+            time_cap: true,
+        }
+    }
+
+    pub fn name(&self) -> String {
+        match self.kind {
+            EngineKind::AlphaBeta => format!("alpha-beta(depth {}, {})", self.depth, self.ctx.evaluator().name()),
+            EngineKind::Mcts => format!(
+                "mcts({}ms x{})",
+                self.mcts.time_limit.as_millis(),
+                self.mcts.threads
+            ),
+        }
+    }
+
+    /// 新しい対局を始める。対局の種を返す
+    pub fn new_game(&mut self) -> u64 {
+        self.mcts.clear();
+        self.fast = false;
+        self.ctx.start_game()
+    }
+
+    /// 現在の盤面で打つ手を選ぶ。合法手がなければ0 (パス)
+    /// パスや一手しかない局面などは探索せずに即答し、その分の時間を残す
+    /// 速指しモードでは探索を浅くし、思考時間の上限も最短にする
+    /// 直前の探索で勝敗がほぼ決まっていれば、思考時間の上限だけを最短にする
+    /// (time_capがfalseなら思考時間の上限は設けない。MCTSは上限とは別にもともと時間で探索を打ち切る)
+    pub fn select_move(&mut self, board: &Board, time_level: usize) -> (u64, [(u64, u64); 4]) {
+        if let Some(mv) = instant_move(board) {
+            let (_, hints) = board.legals();
+            return (mv, hints);
+        }
+        // UIなどが持っている同じフラグをそのまま使い、前の手の中断と期限だけを消す
+        self.ctx.stop.reset();
+        if self.time_cap {
+            let decided = self.ctx.last_score.is_some_and(|s| s.abs() >= DECIDED);
+            self.ctx.stop.stop_after(move_time_limit(if self.fast || decided { 2 } else { time_level }));
+        }
+        match self.kind {
+            EngineKind::AlphaBeta if self.fast => {
+                search(board, self.depth.min(FAST_DEPTH), 0, &mut self.ctx)
+            }
+            EngineKind::AlphaBeta => search(board, self.depth, time_level, &mut self.ctx),
+            EngineKind::Mcts => {
+                if self.fast {
+                    self.ctx.stop.stop_after(self.mcts.time_limit / 4);
+                }
+                let mv = self.mcts.search(board, &mut self.ctx);
+                let (_, hints) = board.legals();
+                (mv, hints)
+            }
+        }
+    }
+}
@@ -0,0 +1,45 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// 1エントリ: check = key ^ data として保存し、読むときに key と一致するか確かめる
+// 他のスレッドと書き込みが混ざっても、一致しなければ外れとして扱うのでロックは要らない
+struct Entry {
+    check: AtomicU64,
+    data: AtomicU64,
+}
+
+/// 末端の評価値のキャッシュ (ロックフリー)
+/// 同じ局面が何度も末端に現れたときに、相手の着手可能数の計算と評価関数の呼び出しを省く
+pub struct EvalCache {
+    entries: Vec<Entry>,
+    mask: usize,
+}
+
+impl EvalCache {
+    /// 2^bits 個のエントリを持つキャッシュを作る
+    pub fn new(bits: u32) -> EvalCache {
+        let size = 1usize << bits;
+        let entries = (0..size)
+            .map(|_| Entry { check: AtomicU64::new(0), data: AtomicU64::new(0) })
+            .collect();
+        EvalCache { entries, mask: size - 1 }
+    }
+
+    pub fn probe(&self, key: u64) -> Option<i32> {
+        let entry = &self.entries[key as usize & self.mask];
+        let data = entry.data.load(Ordering::Relaxed);
+        let check = entry.check.load(Ordering::Relaxed);
+        // 空のエントリ (check = data = 0) は key = 0 のときだけ誤って当たるが、その確率は無視できる
+        if check ^ data == key && (check | data) != 0 {
+            Some(data as u32 as i32)
+        } else {
+            None
+        }
+    }
+
+    pub fn store(&self, key: u64, value: i32) {
+        let entry = &self.entries[key as usize & self.mask];
+        let data = value as u32 as u64;
+        entry.check.store(key ^ data, Ordering::Relaxed);
+        entry.data.store(data, Ordering::Relaxed);
+    }
+}
@@ -1,56 +1,376 @@
+use clap::ValueEnum;
+use std::path::Path;
+use std::sync::Arc;
+
 use crate::bitboard::Board;
+use crate::features;
+use crate::nnue::{Network, Nnue};
+use crate::score;
+use crate::tuner::Tunable;
+use crate::weights::PhaseWeights;
+
+/// 探索の末端で使う評価関数
+/// 手番側から見た値を石差の1/100の単位 (`score::DISC`) で返す (大きいほど手番側が有利)。player_moves, opponent_movesはそれぞれの着手可能位置
+/// 重みから計算した値に倍率 (`scale`) を掛け、終局時の石差の予測になるようにそろえる
+pub trait Evaluator: Send + Sync {
+    fn name(&self) -> &str;
+    fn evaluate(&self, board: &Board, player_moves: u64, opponent_moves: u64) -> i32;
+
+    /// 重みから計算した値に掛ける倍率 (`score::SCALE_ONE`で1倍)
+    fn scale(&self) -> i32 {
+        score::SCALE_ONE
+    }
+
+    /// 評価値の項目ごとの内訳 (`eval`コマンドで表示する)。項目の寄与の合計に倍率を掛けたものがevaluateの値になる
+    fn explain(&self, board: &Board, player_moves: u64, opponent_moves: u64) -> Vec<Term> {
+        vec![Term::new("total", 1, self.evaluate(board, player_moves, opponent_moves), 0)]
+    }
+
+    /// 差分で評価できるネットワークを使う評価関数ならそのネットワーク (探索中にアキュムレータを積むのに使う)
+    fn network(&self) -> Option<Arc<Network>> {
+        None
+    }
+}
+
+/// 評価値の内訳の一項目。寄与は (mine - theirs) * weight
+pub struct Term {
+    pub name: &'static str,
+    pub weight: i32,
+    /// 手番側の値
+    pub mine: i32,
+    /// 相手側の値
+    pub theirs: i32,
+    /// マスごとの寄与 (ビットの位置が添字。マスに結びつかない項目は全部0)
+    pub squares: [i32; 64],
+}
+
+impl Term {
+    pub fn new(name: &'static str, weight: i32, mine: i32, theirs: i32) -> Term {
+        Term { name, weight, mine, theirs, squares: [0; 64] }
+    }
+
+    /// 手番側・相手側それぞれのマスの数を値とする項目
+    pub fn from_bits(name: &'static str, weight: i32, mine: u64, theirs: u64) -> Term {
+        let mut term = Term::new(name, weight, mine.count_ones() as i32, theirs.count_ones() as i32);
+        for (i, square) in term.squares.iter_mut().enumerate() {
+            *square = ((mine >> i & 1) as i32 - (theirs >> i & 1) as i32) * weight;
+        }
+        term
+    }
+
+    pub fn score(&self) -> i32 {
+        (self.mine - self.theirs) * self.weight
+    }
+}
+
+/// 評価関数の種類
+#[derive(Clone, Copy, PartialEq, Eq, Debug, ValueEnum)]
+pub enum EvaluatorKind {
+    /// Corners, squares next to corners and mobility (fast)
+    Heuristic,
+    /// Static value of every square, mobility and corners (slower)
+    Table,
+    /// Small quantized neural network (needs --eval-weights)
+    Nnue,
+}
+
+/// 評価関数を作る。weightsを指定すると、そのファイルから段階ごとの重み (nnueならネットワークの重み) を読み込む
+pub fn make_evaluator(kind: EvaluatorKind, weights: Option<&Path>) -> Result<Arc<dyn Evaluator>, String> {
+    match kind {
+        EvaluatorKind::Nnue => match weights {
+            Some(path) => Ok(Arc::new(Nnue::load(path)?)),
+            None => Err("--eval nnue needs a network weights file (--eval-weights, made by train-nnue)".to_string()),
+        },
+        _ => {
+            let eval: Arc<dyn Tunable> = Arc::from(make_tunable(kind, weights)?);
+            Ok(eval)
+        }
+    }
+}
+
+/// `tune`で調整する評価関数を作る。weightsを指定すると、そこから調整を始める
+pub fn make_tunable(kind: EvaluatorKind, weights: Option<&Path>) -> Result<Box<dyn Tunable>, String> {
+    let mut eval: Box<dyn Tunable> = match kind {
+        EvaluatorKind::Heuristic => Box::new(Heuristic::new()),
+        EvaluatorKind::Table => Box::new(Table::new()),
+        EvaluatorKind::Nnue => return Err("nnue can't be tuned this way (use train-nnue)".to_string()),
+    };
+    if let Some(path) = weights {
+        eval.load(path)?;
+    }
+    Ok(eval)
+}
+
+// 盤上の空きマスの数
+#[inline]
+fn empties(board: &Board) -> usize {
+    64 - (board.player() | board.opponent()).count_ones() as usize
+}
+
+// 石の数の差
+#[inline]
+fn disc_diff(my_stones: u64, opp_stones: u64) -> i32 {
+    my_stones.count_ones() as i32 - opp_stones.count_ones() as i32
+}
+
+// 評価関数ごとの項目の重みの後ろに、特徴量 (features::TERMS) の既定の重みを付け足す
+fn with_features(terms: &[[i32; 3]]) -> Vec<[i32; 3]> {
+    const FEATURES: [[i32; 3]; features::TERMS.len()] = [[0; 3]; features::TERMS.len()];
+    terms.iter().chain(FEATURES.iter()).copied().collect()
+}
+
+/// 石の位置 (角とその隣のみ) と着手可能数だけを見る軽い評価関数
+/// 重みは 角, 隅の隣, 着手可能数, 石数 と`features::TERMS`の順
+pub struct Heuristic {
+    pub weights: PhaseWeights,
+}
+
+impl Heuristic {
+    pub const TERMS: [&'static str; 9] = [
+        "corner",
+        "near_corner",
+        "mobility",
+        "discs",
+        "frontier",
+        "potential_mobility",
+        "parity",
+        "edge_anchors",
+        "wedges",
+    ];
+    // 四隅 (A1, H1, A8, H8)
+    const CORNER: u64 = 0x8100000000000081;
+    // 隅の隣 (XマスとCマス)
+    const NEAR_CORNER: u64 = 0b_01000010_11000011_00000000_00000000_00000000_00000000_11000011_01000010;
+
+    // 既定の重みの倍率 (READMEの`games.txt`で`calibrate`して求めた値)
+    const SCALE: i32 = 2254;
+
+    pub fn new() -> Heuristic {
+        // 手で段階ごとに変えた重みは一定の重みに勝てなかったので、既定では段階によらない
+        Heuristic {
+            weights: PhaseWeights::new(
+                &Heuristic::TERMS,
+                &with_features(&[[256, 256, 256], [-64, -64, -64], [4, 4, 4], [0, 0, 0]]),
+                Heuristic::SCALE,
+            ),
+        }
+    }
+}
+
+impl Evaluator for Heuristic {
+    fn name(&self) -> &str {
+        "heuristic"
+    }
+
+    fn evaluate(&self, board: &Board, player_moves: u64, opponent_moves: u64) -> i32 {
+        let (me, op) = (board.player(), board.opponent());
+        if op == 0 || me == 0 {
+            score::proven(disc_diff(me, op))
+        } else {
+            let w = self.weights.at(empties(board));
+            let raw = disc_diff(me & Heuristic::CORNER, op & Heuristic::CORNER) * w[0]
+                + disc_diff(me & Heuristic::NEAR_CORNER, op & Heuristic::NEAR_CORNER) * w[1]
+                + evaluate_mobility(player_moves, opponent_moves) * w[2]
+                + disc_diff(me, op) * w[3]
+                + features::weighted(me, op, &w[4..]);
+            score::scaled(raw, self.weights.scale())
+        }
+    }
+
+    fn scale(&self) -> i32 {
+        self.weights.scale()
+    }
+
+    fn explain(&self, board: &Board, player_moves: u64, opponent_moves: u64) -> Vec<Term> {
+        let (me, op) = (board.player(), board.opponent());
+        let w = self.weights.at(empties(board));
+        let mut terms = vec![
+            Term::from_bits(Heuristic::TERMS[0], w[0], me & Heuristic::CORNER, op & Heuristic::CORNER),
+            Term::from_bits(Heuristic::TERMS[1], w[1], me & Heuristic::NEAR_CORNER, op & Heuristic::NEAR_CORNER),
+            Term::from_bits(Heuristic::TERMS[2], w[2], player_moves, opponent_moves),
+            Term::from_bits(Heuristic::TERMS[3], w[3], me, op),
+        ];
+        terms.extend(features::explain(me, op, &w[4..]));
+        terms
+    }
+}
 
-// 盤面の各マスが持つ静的な価値。隅は価値が高く、その隣は価値が低い。
-const POSITION_VALUES: [i32; 64] = [
-    120, -20,  20,   5,   5,  20, -20, 120,
-    -20, -40,  -5,  -5,  -5,  -5, -40, -20,
-     20,  -5,  15,   3,   3,  15,  -5,  20,
-      5,  -5,   3,   3,   3,   3,  -5,   5,
-      5,  -5,   3,   3,   3,   3,  -5,   5,
-     20,  -5,  15,   3,   3,  15,  -5,  20,
-    -20, -40,  -5,  -5,  -5,  -5, -40, -20,
-    120, -20,  20,   5,   5,  20, -20, 120,
-];
-
-// 各評価要素の重み
-const POSITION_WEIGHT: i32 = 1;
-const MOBILITY_WEIGHT: i32 = 60;
-const CORNER_WEIGHT: i32 = 800;
+impl Tunable for Heuristic {
+    fn params(&self) -> Vec<i32> {
+        self.weights.params()
+    }
+
+    fn set_params(&mut self, params: &[i32]) {
+        self.weights.set_params(params)
+    }
+
+    fn set_scale(&mut self, scale: i32) {
+        self.weights.set_scale(scale)
+    }
+
+    fn param_name(&self, i: usize) -> String {
+        self.weights.param_name(i)
+    }
+
+    fn load(&mut self, path: &Path) -> Result<(), String> {
+        self.weights.load(path)
+    }
+
+    fn save(&self, path: &Path) -> Result<(), String> {
+        self.weights.save(path)
+    }
+}
+
+/// マスごとの静的な価値のテーブルを使う評価関数 (evaluate_board)
+/// 重みは マスの価値 (SQUARES), 着手可能数, 角, 石数 と`features::TERMS`の順
+pub struct Table {
+    pub weights: PhaseWeights,
+}
+
+impl Table {
+    pub const TERMS: [&'static str; 18] = [
+        "a1",
+        "b1",
+        "c1",
+        "d1",
+        "b2",
+        "c2",
+        "d2",
+        "c3",
+        "d3",
+        "d4",
+        "mobility",
+        "corner",
+        "discs",
+        "frontier",
+        "potential_mobility",
+        "parity",
+        "edge_anchors",
+        "wedges",
+    ];
+
+    // 既定の重みの倍率 (READMEの`games.txt`で`calibrate`して求めた値)
+    const SCALE: i32 = 491;
+
+    pub fn new() -> Table {
+        // マスの価値は隅が高く、その隣は低い
+        let squares = [120, -20, 20, 5, -40, -5, -5, 15, 3, 3].map(|v| [v; 3]);
+        let mut terms = squares.to_vec();
+        terms.extend([[60, 60, 60], [800, 800, 800], [0, 0, 0]]);
+        Table { weights: PhaseWeights::new(&Table::TERMS, &with_features(&terms), Table::SCALE) }
+    }
+}
+
+impl Evaluator for Table {
+    fn name(&self) -> &str {
+        "table"
+    }
+
+    fn evaluate(&self, board: &Board, player_moves: u64, opponent_moves: u64) -> i32 {
+        score::scaled(evaluate_board(board, player_moves, opponent_moves, self.weights.at(empties(board))), self.weights.scale())
+    }
+
+    fn scale(&self) -> i32 {
+        self.weights.scale()
+    }
+
+    fn explain(&self, board: &Board, player_moves: u64, opponent_moves: u64) -> Vec<Term> {
+        let (me, op) = (board.player(), board.opponent());
+        let w = self.weights.at(empties(board));
+        let mut terms: Vec<Term> = SQUARES
+            .iter()
+            .enumerate()
+            .map(|(i, &mask)| Term::from_bits(Table::TERMS[i], w[i], me & mask, op & mask))
+            .collect();
+        terms.push(Term::from_bits(Table::TERMS[10], w[10], player_moves, opponent_moves));
+        terms.push(Term::from_bits(Table::TERMS[11], w[11], me & CORNERS, op & CORNERS));
+        terms.push(Term::from_bits(Table::TERMS[12], w[12], me, op));
+        terms.extend(features::explain(me, op, &w[13..]));
+        terms
+    }
+}
+
+impl Tunable for Table {
+    fn params(&self) -> Vec<i32> {
+        self.weights.params()
+    }
+
+    fn set_params(&mut self, params: &[i32]) {
+        self.weights.set_params(params)
+    }
+
+    fn set_scale(&mut self, scale: i32) {
+        self.weights.set_scale(scale)
+    }
+
+    fn param_name(&self, i: usize) -> String {
+        self.weights.param_name(i)
+    }
+
+    fn load(&mut self, path: &Path) -> Result<(), String> {
+        self.weights.load(path)
+    }
+
+    fn save(&self, path: &Path) -> Result<(), String> {
+        self.weights.save(path)
+    }
+}
+
+// 四隅のビットマスク
+const CORNERS: u64 = 0x8100000000000081;
+
+// 盤面を対称なマスの組 (a1, b1, c1, d1, b2, c2, d2, c3, d3, d4 と、それを回転・反転したマス) に分けたもの
+// 組ごとに同じ価値を持たせる
+const SQUARES: [u64; 10] = square_classes();
+
+const fn square_classes() -> [u64; 10] {
+    let mut classes = [0; 10];
+    let mut i = 0;
+    while i < 64 {
+        // 左上の4x4に折り返し、対角線の上側に寄せる
+        let (row, col) = (i / 8, i % 8);
+        let row = if row > 3 { 7 - row } else { row };
+        let col = if col > 3 { 7 - col } else { col };
+        let (a, b) = if row < col { (row, col) } else { (col, row) };
+        let class = match a {
+            0 => b,
+            1 => 3 + b,
+            2 => 5 + b,
+            _ => 9,
+        };
+        classes[class] |= 1 << i;
+        i += 1;
+    }
+    classes
+}
 
 /// 盤面を評価し、スコアを返すメイン関数
 /// 高速化のため、分岐をなくしループを最適化
-pub fn evaluate_board(board: &Board, black_moves: u64, white_moves: u64) -> i32 {
-    let my_stones = board.get_black();
-    let opp_stones = board.get_white();
+/// weightsは`Table::TERMS`の順の重み
+pub fn evaluate_board(board: &Board, black_moves: u64, white_moves: u64, weights: &[i32]) -> i32 {
+    let my_stones = board.player();
+    let opp_stones = board.opponent();
 
     // 各評価要素を計算
-    let position_score = evaluate_positions(my_stones, opp_stones);
     let mobility_score = evaluate_mobility(black_moves, white_moves);
     let corner_score = evaluate_corners(my_stones, opp_stones);
 
-    // 固定された重み付けで最終スコアを計算。分岐を減らし高速化。
-    position_score * POSITION_WEIGHT
-        + mobility_score * MOBILITY_WEIGHT
-        + corner_score * CORNER_WEIGHT
+    // 局面の段階に応じた重み付けで最終スコアを計算。分岐を減らし高速化。
+    evaluate_positions(my_stones, opp_stones, &weights[..10])
+        + mobility_score * weights[10]
+        + corner_score * weights[11]
+        + disc_diff(my_stones, opp_stones) * weights[12]
+        + features::weighted(my_stones, opp_stones, &weights[13..])
 }
 
-/// 石の配置に基づいてスコアを計算する (高速版)
-/// 盤上の石の数だけループするため、64回固定のループより高速。
-fn evaluate_positions(mut my_stones: u64, mut opp_stones: u64) -> i32 {
-    let mut score = 0;
-    // 自分の石のループ
-    while my_stones != 0 {
-        let index = my_stones.trailing_zeros() as usize;
-        score += POSITION_VALUES[index];
-        my_stones &= my_stones - 1; // 処理済みのビットを消す
-    }
-    // 相手の石のループ
-    while opp_stones != 0 {
-        let index = opp_stones.trailing_zeros() as usize;
-        score -= POSITION_VALUES[index];
-        opp_stones &= opp_stones - 1; // 処理済みのビットを消す
-    }
-    score
+/// 石の配置に基づいてスコアを計算する
+/// マスの組ごとに石の数の差を数えるので、石の数によらず10回のループで済む
+fn evaluate_positions(my_stones: u64, opp_stones: u64, values: &[i32]) -> i32 {
+    SQUARES
+        .iter()
+        .zip(values)
+        .map(|(&mask, &value)| disc_diff(my_stones & mask, opp_stones & mask) * value)
+        .sum()
 }
 
 /// 着手可能数 (モビリティ) に基づいてスコアを計算する
@@ -62,10 +382,8 @@ fn evaluate_mobility(my_moves: u64, opp_moves: u64) -> i32 {
 
 /// 四隅の石に基づいてスコアを計算する
 fn evaluate_corners(my_stones: u64, opp_stones: u64) -> i32 {
-    let corners = 0x8100000000000081; // 四隅のビットマスク
-    
-    let my_corners = (my_stones & corners).count_ones() as i32;
-    let opp_corners = (opp_stones & corners).count_ones() as i32;
+    let my_corners = (my_stones & CORNERS).count_ones() as i32;
+    let opp_corners = (opp_stones & CORNERS).count_ones() as i32;
 
     my_corners - opp_corners
 }
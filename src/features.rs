@@ -0,0 +1,132 @@
+// 盤面の特徴量。どれもビット演算だけで求め、手番側 (me) から見た値を返す
+// 評価関数ではTERMSの名前で重みを付けて足し合わせる (`weighted`)
+
+use crate::evaluator::Term;
+
+const NOT_A_FILE: u64 = 0x7f7f7f7f7f7f7f7f;
+const NOT_H_FILE: u64 = 0xfefefefefefefefe;
+const CORNERS: u64 = 0x8100000000000081;
+// 上下の辺 (1段目と8段目) と左右の辺 (A列とH列)
+const TOP_BOTTOM: u64 = 0xff000000000000ff;
+const LEFT_RIGHT: u64 = 0x8181818181818181;
+
+/// 特徴量の名前 (重みファイルで使う)。`weighted`に渡す重みもこの順
+pub const TERMS: [&str; 5] = ["frontier", "potential_mobility", "parity", "edge_anchors", "wedges"];
+
+// 左右に1マスずらす (端を越えて回り込まないようにする)
+#[inline]
+fn shift_left(bits: u64) -> u64 {
+    (bits & NOT_A_FILE) << 1
+}
+
+#[inline]
+fn shift_right(bits: u64) -> u64 {
+    (bits & NOT_H_FILE) >> 1
+}
+
+/// bitsのどれかと8方向で隣り合うマス
+#[inline]
+pub fn neighbours(bits: u64) -> u64 {
+    let row = bits | shift_left(bits) | shift_right(bits);
+    shift_left(bits) | shift_right(bits) | row << 8 | row >> 8
+}
+
+// 空きマスに接しているownの石
+#[inline]
+fn frontier_discs(own: u64, empty: u64) -> u64 {
+    own & neighbours(empty)
+}
+
+/// 空きマスに接している石 (フロンティア) の数の差 (自分 - 相手)
+/// フロンティアが多いほど相手に打てる場所を与えるので、重みは負にする
+pub fn frontier(me: u64, op: u64) -> i32 {
+    let empty = !(me | op);
+    frontier_discs(me, empty).count_ones() as i32 - frontier_discs(op, empty).count_ones() as i32
+}
+
+// otherの石に接している空きマス (ownがいずれ打てるかもしれないマス)
+#[inline]
+fn potential_squares(other: u64, empty: u64) -> u64 {
+    empty & neighbours(other)
+}
+
+/// 潜在的な着手可能数 (相手の石に接している空きマスの数) の差 (自分 - 相手)
+pub fn potential_mobility(me: u64, op: u64) -> i32 {
+    let empty = !(me | op);
+    potential_squares(op, empty).count_ones() as i32 - potential_squares(me, empty).count_ones() as i32
+}
+
+// 空きマスがつながった領域のうち、マスの数が奇数のものの数
+fn odd_regions(empty: u64) -> i32 {
+    let mut rest = empty;
+    let mut count = 0;
+    while rest != 0 {
+        let mut region = rest & rest.wrapping_neg();
+        loop {
+            let grown = region | neighbours(region) & empty;
+            if grown == region {
+                break;
+            }
+            region = grown;
+        }
+        count += (region.count_ones() & 1) as i32;
+        rest &= !region;
+    }
+    count
+}
+
+/// 空きマスがつながった領域のうち、マスの数が奇数のものの数
+/// 奇数の領域は先に打ち始めた側が最後の一手を打てるので、手番側に有利
+pub fn parity(me: u64, op: u64) -> i32 {
+    odd_regions(!(me | op))
+}
+
+// 自分の角から辺に沿って途切れずに並ぶ石 (角は含まない)
+fn anchored(me: u64) -> u64 {
+    let mut stable = me & CORNERS;
+    for _ in 0..6 {
+        stable |= (shift_left(stable) | shift_right(stable)) & me & TOP_BOTTOM
+            | (stable << 8 | stable >> 8) & me & LEFT_RIGHT;
+    }
+    stable & !CORNERS
+}
+
+/// 角から辺に沿ってつながっていて、もう返されない辺の石の数の差 (自分 - 相手)
+pub fn edge_anchors(me: u64, op: u64) -> i32 {
+    anchored(me).count_ones() as i32 - anchored(op).count_ones() as i32
+}
+
+// 辺の上で両隣がopの石である空きマス (そこに打つと辺に割り込める)
+fn wedge_squares(op: u64, empty: u64) -> u64 {
+    empty & (TOP_BOTTOM & shift_left(op) & shift_right(op) | LEFT_RIGHT & op << 8 & op >> 8)
+}
+
+/// 辺に割り込める空きマスの数の差 (自分が割り込める数 - 相手が割り込める数)
+pub fn wedges(me: u64, op: u64) -> i32 {
+    let empty = !(me | op);
+    wedge_squares(op, empty).count_ones() as i32 - wedge_squares(me, empty).count_ones() as i32
+}
+
+/// TERMSの順の重みで特徴量を足し合わせる。重みが0の特徴量は計算しない
+pub fn weighted(me: u64, op: u64, weights: &[i32]) -> i32 {
+    const FEATURES: [fn(u64, u64) -> i32; 5] = [frontier, potential_mobility, parity, edge_anchors, wedges];
+    FEATURES
+        .iter()
+        .zip(weights)
+        .filter(|(_, &w)| w != 0)
+        .map(|(feature, &w)| feature(me, op) * w)
+        .sum()
+}
+
+/// 特徴量ごとの内訳 (`weighted`と同じ重み)
+pub fn explain(me: u64, op: u64, weights: &[i32]) -> Vec<Term> {
+    let empty = !(me | op);
+    vec![
+        Term::from_bits(TERMS[0], weights[0], frontier_discs(me, empty), frontier_discs(op, empty)),
+        Term::from_bits(TERMS[1], weights[1], potential_squares(op, empty), potential_squares(me, empty)),
+        // パリティはマスに結びつかない
+        Term::new(TERMS[2], weights[2], odd_regions(empty), 0),
+        Term::from_bits(TERMS[3], weights[3], anchored(me), anchored(op)),
+        Term::from_bits(TERMS[4], weights[4], wedge_squares(op, empty), wedge_squares(me, empty)),
+    ]
+}
@@ -0,0 +1,226 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+
+use crate::bitboard::{Board, Undo};
+use crate::cmds::{idx_to_move, move_to_idx, move_to_string, string_to_move, Color, Move};
+
+/// 一局の対局。開始局面から現在までの手順 (パスを含む) を持ち、待ったとやり直しができる
+#[derive(Clone)]
+pub struct Game {
+    start: Board,
+    board: Board,
+    history: Vec<Undo>,
+    // undoで戻した手 (最後に戻した手が末尾)
+    redo: Vec<u64>,
+}
+
+impl Game {
+    /// 初期盤面から始める
+    pub fn new() -> Game {
+        Game::from_board(Board::new())
+    }
+
+    /// 任意の局面から始める
+    pub fn from_board(board: Board) -> Game {
+        Game { start: board.clone(), board, history: Vec::new(), redo: Vec::new() }
+    }
+
+    pub fn board(&self) -> &Board {
+        &self.board
+    }
+
+    /// 手番側の色
+    pub fn side(&self) -> Color {
+        self.board.side()
+    }
+
+    /// 開始局面からの手順 (0はパス)
+    pub fn moves(&self) -> Vec<u64> {
+        self.history.iter().map(|undo| undo.mv).collect()
+    }
+
+    /// 手を打つ (0はパス)。パスは合法手がないときだけ認める
+    /// 新しい手を打つと、やり直し用に取っておいた手は捨てる
+    pub fn play(&mut self, mv: u64) -> Result<(), String> {
+        self.play_inner(mv)?;
+        self.redo.clear();
+        Ok(())
+    }
+
+    fn play_inner(&mut self, mv: u64) -> Result<(), String> {
+        let (legals, hints) = self.board.legals();
+        let undo = if mv == 0 {
+            if legals != 0 {
+                return Err(format!("illegal pass at {}", self.history.len() + 1));
+            }
+            self.board.make_pass()
+        } else {
+            if mv.count_ones() != 1 || legals & mv == 0 {
+                return Err(format!("illegal move {} at {}", move_to_string(idx_to_move(&mv)), self.history.len() + 1));
+            }
+            self.board.make_move(mv, hints)
+        };
+        self.history.push(undo);
+        Ok(())
+    }
+
+    /// 一手戻し、戻した手を返す
+    pub fn undo(&mut self) -> Option<u64> {
+        let undo = self.history.pop()?;
+        self.board.unmake_move(undo);
+        self.redo.push(undo.mv);
+        Some(undo.mv)
+    }
+
+    /// undoで戻した手を打ち直し、その手を返す
+    #[allow(dead_code)]
+    pub fn redo(&mut self) -> Option<u64> {
+        let mv = self.redo.pop()?;
+        self.play_inner(mv).expect("redo move must be legal");
+        Some(mv)
+    }
+
+    /// 両者とも打てる手がなければ終局
+    pub fn is_over(&self) -> bool {
+        let (legals, _) = self.board.legals();
+        if legals != 0 {
+            return false;
+        }
+        let mut board = self.board.clone();
+        board.exchange();
+        board.legals().0 == 0
+    }
+
+    /// (黒の石数, 白の石数)
+    pub fn score(&self) -> (u32, u32) {
+        (self.board.black().count_ones(), self.board.white().count_ones())
+    }
+
+    /// 終局していれば勝った色を返す (引き分けはColor::Empty)
+    pub fn winner(&self) -> Option<Color> {
+        if !self.is_over() {
+            return None;
+        }
+        let (black, white) = self.score();
+        Some(match black.cmp(&white) {
+            std::cmp::Ordering::Greater => Color::Black,
+            std::cmp::Ordering::Less => Color::White,
+            std::cmp::Ordering::Equal => Color::Empty,
+        })
+    }
+
+    /// 棋譜の文字列。`# 名前: 値`の見出し行 (tagsと、初期盤面以外から始めたときの開始局面) と
+    /// "F5 D6 C3 PASS ..." の形の手順の行からなり、`from_transcript`で読み戻せる
+    pub fn transcript(&self, tags: &[(&str, String)]) -> String {
+        let mut s = String::new();
+        for (name, value) in tags.iter() {
+            s += &format!("# {}: {}\n", name, value);
+        }
+        if self.start != Board::new() {
+            s += &format!("# position: {}\n", self.start);
+        }
+        let moves: Vec<String> = self.moves().iter().map(|mv| move_to_string(idx_to_move(mv))).collect();
+        s += &moves.join(" ");
+        s.push('\n');
+        s
+    }
+
+    /// 棋譜を読んで対局を再生する。"f5d6c3" のように空白やPASSを省いた手順も読める
+    /// 手番側に合法手がなければ、PASSが書かれていなくても自動でパスする
+    pub fn from_transcript(text: &str) -> Result<Game, String> {
+        let mut start = Board::new();
+        let mut moves = String::new();
+        for line in text.lines() {
+            match line.strip_prefix('#') {
+                Some(tag) => {
+                    if let Some(position) = tag.trim().strip_prefix("position:") {
+                        start = position.trim().parse()?;
+                    }
+                }
+                None => moves.extend(line.chars().filter(|c| !c.is_whitespace())),
+            }
+        }
+        let mut game = Game::from_board(start);
+        let mut rest = moves.as_str();
+        let mut ply = 0;
+        while !rest.is_empty() {
+            // 手は2文字かPASS (ASCII以外の文字が混じっていても文字の途中で切らない)
+            let len = if rest.get(..4).is_some_and(|s| s.eq_ignore_ascii_case("PASS")) {
+                4
+            } else {
+                rest.char_indices().nth(2).map_or(rest.len(), |(i, _)| i)
+            };
+            let token = &rest[..len];
+            rest = &rest[len..];
+            ply += 1;
+            let mv = string_to_move(token).ok_or(format!("invalid move '{}' at {}", token, ply))?;
+            if mv != Move::Pass && game.board.legals().0 == 0 {
+                // 書かれていないパスを補う
+                game.play(0)?;
+            }
+            game.play(move_to_idx(&mv))?;
+        }
+        Ok(game)
+    }
+
+    /// 棋譜をファイルに追記する (対局の間は空行で区切る)
+    pub fn append_transcript(&self, path: &Path, tags: &[(&str, String)]) {
+        let result = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .and_then(|mut f| f.write_all(format!("{}\n", self.transcript(tags)).as_bytes()));
+        if let Err(e) = result {
+            eprintln!("[Warn] Failed to append to {}: {}", path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 白の手番で白には合法手がなく、黒はd1に打てる局面
+    const PASS_POSITION: &str = "XOO------------------------------------------------------------- O";
+
+    #[test]
+    fn transcript_round_trip() {
+        let game = Game::from_transcript("f5d6c3d3c4").unwrap();
+        assert_eq!(game.moves().len(), 5);
+        let text = game.transcript(&[("black", "a".to_string())]);
+        assert_eq!(text, "# black: a\nF5 D6 C3 D3 C4\n");
+        let again = Game::from_transcript(&text).unwrap();
+        assert_eq!(again.moves(), game.moves());
+        assert!(again.board() == game.board());
+    }
+
+    #[test]
+    fn transcript_with_position_and_pass() {
+        let text = format!("# position: {}\nPASS D1\n", PASS_POSITION);
+        let game = Game::from_transcript(&text).unwrap();
+        assert_eq!(game.moves(), vec![0, move_to_idx(&Move::To(4, 1))]);
+        assert!(game.is_over());
+        assert_eq!(game.transcript(&[]), text);
+        // 書かれていないパスは補う
+        let implicit = Game::from_transcript(&format!("# position: {}\nd1", PASS_POSITION)).unwrap();
+        assert_eq!(implicit.moves(), game.moves());
+    }
+
+    #[test]
+    fn transcript_rejects_invalid_moves() {
+        assert!(Game::from_transcript("f5d6x9").is_err());
+        assert!(Game::from_transcript("f5d").is_err());
+        // 打てない手とパス
+        assert!(Game::from_transcript("a1").is_err());
+        assert!(Game::from_transcript("pass").is_err());
+    }
+
+    #[test]
+    fn transcript_with_non_ascii_is_an_error() {
+        assert!(Game::from_transcript("f5あ").is_err());
+        assert!(Game::from_transcript("あf5").is_err());
+        assert!(Game::from_transcript("f5dあ").is_err());
+        assert!(Game::from_transcript("paあs").is_err());
+    }
+}
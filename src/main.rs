@@ -1,8 +1,10 @@
 
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use std::io::{BufRead, BufReader, BufWriter, Write};
 use std::net::{TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use tailcall::tailcall;
 
 mod cmds;
@@ -10,19 +12,41 @@ use cmds::{idx_to_move, move_to_idx, move_to_string, Cmd, Color, Res};
 mod parse;
 use parse::{parse, tokenize};
 mod bitboard;
-use bitboard::Board;
 mod search;
-use search::search;
+mod engine;
+use engine::{Engine, EngineKind};
+use search::{SearchContext, SearchParams};
+use evaluator::{make_evaluator, make_tunable, EvaluatorKind};
+mod mcts;
+use mcts::Mcts;
+mod arena;
+mod opponent;
+mod analysis;
+use opponent::{OpponentModel, Outcome};
 // add book
 mod book;
 // add evaluator
 mod evaluator;
+mod features;
+mod nnue;
+mod dataset;
+mod tuner;
+mod td;
+mod weights;
+mod score;
 // add trans
 mod transposition;
+mod evalcache;
+mod solved;
+mod bench;
+mod symmetry;
+mod game;
+use game::Game;
 
 /// Reversi Command Line Interface 
 // (この部分はIS23er: Luhao Liu <luhao.liu@a.riken.jp>の実装を参考しました。)
 #[derive(Parser)]
+#[command(subcommand_negates_reqs = true)]
 struct Args {
     /// Hostname
     #[arg(short = 'H', default_value = "localhost")]
@@ -33,8 +57,252 @@ struct Args {
     port: u16,
 
     /// Player Name
-    #[arg(short = 'n')]
-    player: String,
+    #[arg(short = 'n', required = true)]
+    player: Option<String>,
+
+    /// Random seed (chosen randomly and logged if omitted)
+    #[arg(long, global = true)]
+    seed: Option<u64>,
+
+    /// Turn off the per-move time cap so that --seed reproduces games exactly (for benchmarks and tests; never for server games)
+    #[arg(long, global = true)]
+    no_time_cap: bool,
+
+    /// Search engine
+    #[arg(long, value_enum, default_value_t = EngineKind::AlphaBeta)]
+    engine: EngineKind,
+
+    /// Number of MCTS threads
+    #[arg(long, default_value = "1", global = true)]
+    threads: usize,
+
+    /// MCTS time per move in milliseconds
+    #[arg(long, default_value = "1000", global = true)]
+    mcts_time: u64,
+
+    /// Maximum number of MCTS tree nodes per thread (the tree stops growing there)
+    #[arg(long, default_value = "500000", global = true)]
+    mcts_nodes: usize,
+
+    /// Evaluation function
+    #[arg(long = "eval", value_enum, default_value_t = EvaluatorKind::Heuristic, global = true)]
+    evaluator: EvaluatorKind,
+
+    /// File with per-phase evaluation weights (lines of "name opening midgame late")
+    #[arg(long, global = true)]
+    eval_weights: Option<PathBuf>,
+
+    /// Search parameter as name=value (e.g. lmr_reduction=2), repeatable
+    #[arg(long = "param", global = true)]
+    params: Vec<String>,
+
+    /// File to keep per-opponent statistics in
+    #[arg(long, default_value = "opponents.txt")]
+    opponent_db: PathBuf,
+
+    /// File to keep solved endgame positions in
+    #[arg(long, default_value = "solved.txt", global = true)]
+    solved_cache: PathBuf,
+
+    /// File to append game transcripts to
+    #[arg(long, global = true)]
+    transcripts: Option<PathBuf>,
+
+    /// Maximum number of solved positions to keep
+    #[arg(long, default_value = "100000", global = true)]
+    solved_limit: usize,
+
+    /// Opening book file (lines of moves like f5d6c3, or the binary format of `book convert`); the built-in book if omitted
+    #[arg(long, global = true)]
+    book: Option<PathBuf>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Play two engines against each other locally
+    Match {
+        /// First engine (black in odd-numbered games)
+        #[arg(long, value_enum, default_value_t = EngineKind::AlphaBeta)]
+        first: EngineKind,
+
+        /// Second engine
+        #[arg(long, value_enum, default_value_t = EngineKind::Mcts)]
+        second: EngineKind,
+
+        /// Number of games
+        #[arg(long, default_value = "10")]
+        games: usize,
+
+        /// Alpha-beta search depth
+        #[arg(long, default_value = "6")]
+        depth: usize,
+
+        /// Evaluation function of the first engine (defaults to --eval)
+        #[arg(long, value_enum)]
+        first_eval: Option<EvaluatorKind>,
+
+        /// Evaluation function of the second engine (defaults to --eval)
+        #[arg(long, value_enum)]
+        second_eval: Option<EvaluatorKind>,
+
+        /// Evaluation weights file of the first engine (defaults to --eval-weights)
+        #[arg(long)]
+        first_weights: Option<PathBuf>,
+
+        /// Evaluation weights file of the second engine (defaults to --eval-weights)
+        #[arg(long)]
+        second_weights: Option<PathBuf>,
+    },
+    /// Score every legal move of a position (multi-PV)
+    Analyze {
+        /// Moves from the initial position (e.g. f5d6c3)
+        #[arg(default_value = "")]
+        moves: String,
+
+        /// Position as 64 squares (X, O or -, A1 to H8) and the side to move, or a file holding one or a diagram
+        #[arg(long, conflicts_with = "moves", allow_hyphen_values = true)]
+        position: Option<String>,
+
+        /// Search depth
+        #[arg(long, default_value = "8")]
+        depth: usize,
+
+        /// Number of moves to score exactly (0 = all)
+        #[arg(long, default_value = "0")]
+        multipv: usize,
+
+        /// Analyze the position this many moves before the end of the transcript
+        #[arg(long, default_value = "0")]
+        back: usize,
+    },
+    /// Show each term of the evaluation of a position and its contribution per square
+    Eval {
+        /// Moves from the initial position (e.g. f5d6c3)
+        #[arg(default_value = "")]
+        moves: String,
+
+        /// Position as 64 squares (X, O or -, A1 to H8) and the side to move, or a file holding one or a diagram
+        #[arg(long, conflicts_with = "moves", allow_hyphen_values = true)]
+        position: Option<String>,
+
+        /// Evaluate the position this many moves before the end of the transcript
+        #[arg(long, default_value = "0")]
+        back: usize,
+    },
+    /// Remove duplicates from the solved-position file and trim it to --solved-limit
+    SolvedCompact,
+    /// Search fixed positions and report nodes per second
+    Bench {
+        /// Search depth
+        #[arg(long, default_value = "8")]
+        depth: usize,
+    },
+    /// Tune the weights of the evaluation function (--eval, starting from --eval-weights) to predict game results
+    Tune {
+        /// Files of "<position> <side> <disc difference>" lines and/or game transcripts
+        #[arg(required = true)]
+        data: Vec<PathBuf>,
+
+        /// File to write the tuned weights to (rewritten after every iteration)
+        #[arg(long, default_value = "weights.txt")]
+        out: PathBuf,
+
+        /// Maximum number of passes over all weights
+        #[arg(long, default_value = "50")]
+        iterations: usize,
+    },
+    /// Fit the output scale of the evaluation function (--eval, --eval-weights) to final disc differences and the win-probability slope to game results
+    Calibrate {
+        /// Files of "<position> <side> <disc difference>" lines and/or game transcripts
+        #[arg(required = true)]
+        data: Vec<PathBuf>,
+    },
+    /// Learn the evaluation weights (--eval, starting from --eval-weights) by TD(lambda) self-play, resuming from the latest checkpoint in --dir
+    TrainTd {
+        /// Directory for checkpoints (ckpt-<games>.txt) and log.txt
+        #[arg(long, default_value = "td")]
+        dir: PathBuf,
+
+        /// Total number of self-play games
+        #[arg(long, default_value = "10000")]
+        games: usize,
+
+        /// Stop after this many hours (finishing the current game and writing a checkpoint)
+        #[arg(long)]
+        hours: Option<f64>,
+
+        /// Alpha-beta search depth of the self-play games
+        #[arg(long, default_value = "4")]
+        depth: usize,
+
+        /// Learning rate
+        #[arg(long, default_value = "200")]
+        lr: f64,
+
+        /// Trace decay (0 = learn from the next position only, 1 = from the game result only)
+        #[arg(long, default_value = "0.7")]
+        lambda: f64,
+
+        /// Number of random moves at the start of each game
+        #[arg(long, default_value = "8")]
+        random_plies: usize,
+
+        /// Games between checkpoints
+        #[arg(long, default_value = "200")]
+        checkpoint_every: usize,
+
+        /// Games against the first and the previous checkpoint at every checkpoint (0 = none)
+        #[arg(long, default_value = "20")]
+        match_games: usize,
+    },
+    /// Train the neural-network evaluator (--eval nnue) from labelled positions or transcripts
+    TrainNnue {
+        /// Files of "<position> <side> <disc difference>" lines and/or game transcripts
+        #[arg(required = true)]
+        data: Vec<PathBuf>,
+
+        /// File to write the network weights to
+        #[arg(long, default_value = "nnue.txt")]
+        out: PathBuf,
+
+        /// Number of passes over the training positions
+        #[arg(long, default_value = "20")]
+        epochs: usize,
+
+        /// Learning rate
+        #[arg(long, default_value = "0.005")]
+        lr: f32,
+    },
+    /// Opening book tools
+    Book {
+        #[command(subcommand)]
+        command: BookCommand,
+    },
+}
+
+#[derive(Subcommand)]
+enum BookCommand {
+    /// Report invalid, duplicate and conflicting lines of an opening book and count its positions per move
+    Check {
+        /// Book to check, text or binary (the built-in book if omitted)
+        file: Option<PathBuf>,
+    },
+    /// Convert an opening book between the text and the binary format
+    Convert {
+        /// File to write
+        output: PathBuf,
+
+        /// Book to read, text or binary (the built-in book if omitted)
+        #[arg(long)]
+        from: Option<PathBuf>,
+
+        /// Write the text format instead of the binary one
+        #[arg(long)]
+        text: bool,
+    },
 }
 
 enum State {
@@ -91,83 +359,139 @@ fn print_scores(scores: Vec<(String, (i32, i32, i32))>) {
     }
 }
 
+// サーバーが報告する終局の理由のうち、両者とも打てなくなった普通の終局
+const NORMAL_END: &str = "DOUBLE_PASS";
+
+// 棋譜のresultの見出し。普通に終局した対局は学習データの石差に使えるよう "黒の石数 - 白の石数" で始める
+// (時間切れなどで終わった対局の石数は終局時のものではないので、そう書かない)
+fn result_tag(res: &Res, color: Color, mine: i32, theirs: i32, reason: &str) -> String {
+    if reason == NORMAL_END {
+        let (black, white) = if color == Color::White { (theirs, mine) } else { (mine, theirs) };
+        format!("{} - {} ({:?})", black, white, res)
+    } else {
+        format!("{:?} ({} vs {}, {})", res, mine, theirs, reason)
+    }
+}
+
+// 対局の終わりに種と棋譜を表示・保存し、次の対局に備える
+// 最後の手はサーバーから送られてこないことがあるので、結果はサーバーの報告 (result) を記録する
+fn finish_game(
+    engine: &mut Engine,
+    opponents: &mut OpponentModel,
+    current: &mut Game,
+    transcripts: Option<&Path>,
+    outcome: Outcome,
+    result: String,
+) {
+    let seed = engine.ctx.game_seed();
+    println!("Seed: {}", seed);
+    println!("Moves: {}", current.transcript(&[]).trim_end());
+    if let Some(path) = transcripts {
+        current.append_transcript(path, &[
+            ("opponent", opponents.name().to_string()),
+            ("engine", format!("{} (seed: {})", engine.name(), seed)),
+            ("result", result),
+        ]);
+    }
+    opponents.finish_game(outcome);
+    *current = Game::new();
+}
+
 #[tailcall]
+#[allow(clippy::too_many_arguments)]
 fn game(
     state: State,
     reader: &mut BufReader<&TcpStream>,
     writer: &mut BufWriter<&TcpStream>,
+    engine: &mut Engine,
+    opponents: &mut OpponentModel,
     color: Color,
-    board: &mut Board,
+    current: &mut Game,
+    transcripts: Option<&Path>,
     oppo_name: String,
     time_level: usize,
 ) {
-    const DEFAULT_DEPTH: usize = 10;
     match state {
         State::WaitingStart => match read_cmd(reader) {
             Cmd::Bye(scores) => {
                 print_scores(scores);
             }
-            Cmd::Start(color, oppo_name, _) => match color {
-                Color::Black => game(
-                    State::MyMove,
-                    reader,
-                    writer,
-                    Color::Black,
-                    board,
-                    oppo_name,
-                    time_level,
-                ),
-                Color::White => game(
-                    State::OpMove,
-                    reader,
-                    writer,
-                    Color::White,
-                    board,
-                    oppo_name,
-                    time_level,
-                ),
-                _ => {
-                    panic!("Invalid Command");
+            Cmd::Start(color, oppo_name, _) => {
+                let seed = engine.new_game();
+                println!("Game start vs {} (seed: {})", oppo_name, seed);
+                opponents.start_game(&oppo_name);
+                engine.fast = opponents.is_weak();
+                match color {
+                    Color::Black => game(
+                        State::MyMove,
+                        reader,
+                        writer,
+                        engine,
+                        opponents,
+                        Color::Black,
+                        current,
+                        transcripts,
+                        oppo_name,
+                        time_level,
+                    ),
+                    Color::White => game(
+                        State::OpMove,
+                        reader,
+                        writer,
+                        engine,
+                        opponents,
+                        Color::White,
+                        current,
+                        transcripts,
+                        oppo_name,
+                        time_level,
+                    ),
+                    _ => {
+                        panic!("Invalid Command");
+                    }
                 }
-            },
+            }
             _ => {
                 panic!("Invalid Command");
             }
         },
         State::MyMove => {
-            let (mv, hints) = search(board, DEFAULT_DEPTH, time_level);
+            let (mv, _) = engine.select_move(current.board(), time_level);
             write_cmd(writer, Cmd::Move(idx_to_move(&mv)));
-            if mv != 0 {
-                board.next(mv, hints);
-            }
+            current.play(mv).expect("engine played an illegal move");
             #[cfg(debug_assertions)]
-            board.print();
-            board.exchange();
+            current.board().print();
             game(
                 State::WaitingAck,
                 reader,
                 writer,
+                engine,
+                opponents,
                 color,
-                board,
+                current,
+                transcripts,
                 oppo_name,
                 time_level,
             )
         }
         State::OpMove => match read_cmd(reader) {
             Cmd::Move(mv) => {
-                let (_, hints) = board.legals();
-                if move_to_idx(&mv) != 0 {
-                    board.next(move_to_idx(&mv), hints);
+                opponents.observe(current.board(), move_to_idx(&mv));
+                engine.fast = opponents.is_weak();
+                if let Err(e) = current.play(move_to_idx(&mv)) {
+                    eprintln!("[Warn] Opponent move: {}", e);
                 }
-                board.exchange();
                 #[cfg(debug_assertions)]
-                board.print();
+                current.board().print();
                 game(
                     State::MyMove,
                     reader,
                     writer,
+                    engine,
+                    opponents,
                     color,
-                    board,
+                    current,
+                    transcripts,
                     oppo_name,
                     time_level,
                 )
@@ -178,13 +502,27 @@ fn game(
                     Res::Lose => println!("You Lose. ({} vs {}), {}", n, m, r),
                     Res::Tie => println!("Draw. ({} vs {}), {}", n, m, r),
                 };
-                board.clear();
+                finish_game(
+                    engine,
+                    opponents,
+                    current,
+                    transcripts,
+                    match res {
+                        Res::Win => Outcome::Win,
+                        Res::Lose => Outcome::Lose,
+                        Res::Tie => Outcome::Draw,
+                    },
+                    result_tag(&res, color, n, m, &r),
+                );
                 game(
                     State::WaitingStart,
                     reader,
                     writer,
+                    engine,
+                    opponents,
                     Color::Empty,
-                    board,
+                    current,
+                    transcripts,
                     oppo_name,
                     0,
                 )
@@ -197,13 +535,16 @@ fn game(
             Cmd::Ack(time) => {
                 if time < 0 {
                     println!("You Lose. Time Up.");
-                    board.clear();
+                    finish_game(engine, opponents, current, transcripts, Outcome::Lose, "Lose (time up)".to_string());
                     game(
                         State::WaitingStart,
                         reader,
                         writer,
+                        engine,
+                        opponents,
                         Color::Empty,
-                        board,
+                        current,
+                        transcripts,
                         oppo_name,
                         0,
                     )
@@ -212,8 +553,11 @@ fn game(
                         State::OpMove,
                         reader,
                         writer,
+                        engine,
+                        opponents,
                         color,
-                        board,
+                        current,
+                        transcripts,
                         oppo_name,
                         if time > 30000 {
                             0
@@ -231,13 +575,27 @@ fn game(
                     Res::Lose => println!("You Lose. ({} vs {}), {}", n, m, r),
                     Res::Tie => println!("Draw. ({} vs {}), {}", n, m, r),
                 };
-                board.clear();
+                finish_game(
+                    engine,
+                    opponents,
+                    current,
+                    transcripts,
+                    match res {
+                        Res::Win => Outcome::Win,
+                        Res::Lose => Outcome::Lose,
+                        Res::Tie => Outcome::Draw,
+                    },
+                    result_tag(&res, color, n, m, &r),
+                );
                 game(
                     State::WaitingStart,
                     reader,
                     writer,
+                    engine,
+                    opponents,
                     Color::Empty,
-                    board,
+                    current,
+                    transcripts,
                     oppo_name,
                     0,
                 )
@@ -250,8 +608,210 @@ fn game(
 }
 
 fn main() {
+    // ネットワーク対局での探索の深さ
+    const DEFAULT_DEPTH: usize = 10;
+
     let args = Args::parse();
-    println!("Player Name: {}", &args.player);
+    let seed = args.seed.unwrap_or_else(rand::random);
+    let mcts = || Mcts::new(args.threads, Duration::from_millis(args.mcts_time), args.mcts_nodes);
+    // 思考時間の上限は`--no-time-cap`で明示的に外したときだけ外す (種を指定しても時間切れは避ける)
+    let time_cap = !args.no_time_cap;
+    let mut params = SearchParams::default();
+    for assignment in args.params.iter() {
+        if let Err(e) = params.set(assignment) {
+            eprintln!("Error: {}", e);
+            return;
+        }
+    }
+
+    if let Some(path) = &args.book {
+        if let Err(e) = book::load_opening_book(path) {
+            eprintln!("Error: {}", e);
+            return;
+        }
+    }
+
+    let evaluator = match make_evaluator(args.evaluator, args.eval_weights.as_deref()) {
+        Ok(evaluator) => evaluator,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            return;
+        }
+    };
+
+    match args.command {
+        Some(Command::SolvedCompact) => {
+            match solved::compact(&args.solved_cache, args.solved_limit) {
+                Ok((before, after)) => println!("{}: {} lines -> {} positions", args.solved_cache.display(), before, after),
+                Err(e) => eprintln!("Error: {}: {}", args.solved_cache.display(), e),
+            }
+            return;
+        }
+        Some(Command::Match { first, second, games, depth, first_eval, second_eval, first_weights, second_weights }) => {
+            solved::SOLVED.open(&args.solved_cache, args.solved_limit);
+            println!("Seed: {}", seed);
+            let mut first = Engine::new(first, depth, seed, mcts());
+            let mut second = Engine::new(second, depth, !seed, mcts());
+            first.ctx.params = params.clone();
+            second.ctx.params = params;
+            first.time_cap = time_cap;
+            second.time_cap = time_cap;
+            let weights = |path: Option<PathBuf>| path.or_else(|| args.eval_weights.clone());
+            let first_evaluator = make_evaluator(first_eval.unwrap_or(args.evaluator), weights(first_weights).as_deref());
+            let second_evaluator = make_evaluator(second_eval.unwrap_or(args.evaluator), weights(second_weights).as_deref());
+            match (first_evaluator, second_evaluator) {
+                (Ok(e1), Ok(e2)) => {
+                    first.ctx.set_evaluator(e1);
+                    second.ctx.set_evaluator(e2);
+                }
+                (Err(e), _) | (_, Err(e)) => {
+                    eprintln!("Error: {}", e);
+                    return;
+                }
+            }
+            arena::run_match(&mut first, &mut second, games, args.transcripts.as_deref());
+            return;
+        }
+        Some(Command::Bench { depth }) => {
+            // 比較しやすいように、種を指定しなければ0を使う
+            let seed = args.seed.unwrap_or(0);
+            println!("Seed: {}", seed);
+            let mut ctx = SearchContext::new(seed);
+            ctx.params = params;
+            ctx.set_evaluator(evaluator.clone());
+            bench::run_bench(depth, &mut ctx);
+            return;
+        }
+        Some(Command::Tune { data, out, iterations }) => {
+            let samples = match dataset::load_all(&data) {
+                // 終局していない棋譜は学習に使わず、その数も表示する
+                Ok(dataset) => {
+                    println!(
+                        "Loaded {} positions from {} games ({} unfinished games skipped)",
+                        dataset.samples.len(),
+                        dataset.games,
+                        dataset.skipped.len()
+                    );
+                    dataset.samples
+                }
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    return;
+                }
+            };
+            let mut tunable = match make_tunable(args.evaluator, args.eval_weights.as_deref()) {
+                Ok(tunable) => tunable,
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    return;
+                }
+            };
+            let seed = args.seed.unwrap_or(0);
+            println!("Seed: {}", seed);
+            if let Err(e) = tuner::tune(tunable.as_mut(), &samples, &out, iterations, seed) {
+                eprintln!("Error: {}", e);
+            }
+            return;
+        }
+        Some(Command::Calibrate { data }) => {
+            let result = dataset::load_all(&data).and_then(|dataset| {
+                println!(
+                    "Loaded {} positions from {} games ({} unfinished games skipped)",
+                    dataset.samples.len(),
+                    dataset.games,
+                    dataset.skipped.len()
+                );
+                tuner::calibrate(evaluator.as_ref(), &dataset.samples)
+            });
+            if let Err(e) = result {
+                eprintln!("Error: {}", e);
+            }
+            return;
+        }
+        Some(Command::TrainTd { dir, games, hours, depth, lr, lambda, random_plies, checkpoint_every, match_games }) => {
+            solved::SOLVED.open(&args.solved_cache, args.solved_limit);
+            let seed = args.seed.unwrap_or(0);
+            println!("Seed: {}", seed);
+            let mut learner = Engine::new(EngineKind::AlphaBeta, depth, seed, mcts());
+            let mut opponent = Engine::new(EngineKind::AlphaBeta, depth, !seed, mcts());
+            learner.ctx.params = params.clone();
+            opponent.ctx.params = params;
+            learner.time_cap = time_cap;
+            opponent.time_cap = time_cap;
+            let options = td::TdOptions {
+                kind: args.evaluator,
+                dir,
+                games,
+                time_limit: hours.map(|h| Duration::from_secs_f64(h * 3600.0)),
+                lr,
+                lambda,
+                random_plies,
+                checkpoint_every: checkpoint_every.max(1),
+                match_games,
+                seed,
+            };
+            if let Err(e) = td::train(&options, args.eval_weights.as_deref(), &mut learner, &mut opponent) {
+                eprintln!("Error: {}", e);
+            }
+            return;
+        }
+        Some(Command::TrainNnue { data, out, epochs, lr }) => {
+            let samples = match dataset::load_all(&data) {
+                Ok(dataset) => dataset.samples,
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    return;
+                }
+            };
+            let seed = args.seed.unwrap_or(0);
+            println!("Seed: {}", seed);
+            if let Err(e) = nnue::train(&samples, &out, epochs, lr, seed) {
+                eprintln!("Error: {}", e);
+            }
+            return;
+        }
+        Some(Command::Analyze { moves, position, depth, multipv, back }) => {
+            match analysis::position_from_args(&moves, position.as_deref(), back) {
+                Ok(board) => {
+                    let mut ctx = SearchContext::new(seed);
+                    ctx.params = params;
+                    ctx.set_evaluator(evaluator.clone());
+                    analysis::analyze(&board, depth, multipv, &mut ctx)
+                }
+                Err(e) => eprintln!("Error: {}", e),
+            }
+            return;
+        }
+        Some(Command::Book { command: BookCommand::Check { file } }) => {
+            if let Err(e) = book::print_check(file.as_deref()) {
+                eprintln!("Error: {}", e);
+            }
+            return;
+        }
+        Some(Command::Book { command: BookCommand::Convert { output, from, text } }) => {
+            let lines = match from {
+                Some(path) => book::Book::load(&path).map(|book| book.lines().to_vec()),
+                None => Ok(book::Book::embedded().lines().to_vec()),
+            };
+            match lines.and_then(|lines| book::write_lines(&output, &lines, !text).map(|()| lines.len())) {
+                Ok(n) => println!("Wrote {} lines to {}", n, output.display()),
+                Err(e) => eprintln!("Error: {}", e),
+            }
+            return;
+        }
+        Some(Command::Eval { moves, position, back }) => {
+            match analysis::position_from_args(&moves, position.as_deref(), back) {
+                Ok(board) => analysis::explain(&board, evaluator.as_ref()),
+                Err(e) => eprintln!("Error: {}", e),
+            }
+            return;
+        }
+        None => {}
+    }
+
+    let player = args.player.clone().unwrap();
+    println!("Player Name: {}", &player);
+    println!("Seed: {}", seed);
 
     let addr = (args.hostname.clone(), args.port)
         .to_socket_addrs()
@@ -266,14 +826,23 @@ fn main() {
     let mut reader = BufReader::new(&stream);
     let mut writer = BufWriter::new(&stream);
 
-    write_cmd(&mut writer, Cmd::Open(args.player.clone()));
+    solved::SOLVED.open(&args.solved_cache, args.solved_limit);
+    let mut engine = Engine::new(args.engine, DEFAULT_DEPTH, seed, mcts());
+    engine.ctx.params = params;
+    engine.ctx.set_evaluator(evaluator.clone());
+    engine.time_cap = time_cap;
+
+    write_cmd(&mut writer, Cmd::Open(player.clone()));
     game(
         State::WaitingStart,
         &mut reader,
         &mut writer,
+        &mut engine,
+        &mut OpponentModel::load(args.opponent_db.clone()),
         Color::Empty,
-        &mut Board::new(),
-        args.player.clone(),
+        &mut Game::new(),
+        args.transcripts.as_deref(),
+        player,
         0,
     );
 }
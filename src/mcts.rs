@@ -0,0 +1,304 @@
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::bitboard::Board;
+use crate::search::SearchContext;
+
+// 四隅のビットマスク
+const CORNERS: u64 = 0x8100000000000081;
+
+// 木のノード
+// winsは「このノードに至る手を打った側」から見た勝ち数 (引き分けは0.5)
+struct Node {
+    board: Board,
+    mv: u64,
+    children: Vec<usize>,
+    untried: u64,
+    pass_pending: bool,
+    terminal: bool,
+    visits: u32,
+    wins: f64,
+}
+
+impl Node {
+    fn new(board: Board, mv: u64) -> Node {
+        let (legals, _) = board.legals();
+        let mut node = Node {
+            board,
+            mv,
+            children: Vec::new(),
+            untried: legals,
+            pass_pending: false,
+            terminal: false,
+            visits: 0,
+            wins: 0.0,
+        };
+        if legals == 0 {
+            let mut opp = node.board.clone();
+            opp.exchange();
+            let (opp_legals, _) = opp.legals();
+            if opp_legals == 0 {
+                node.terminal = true;
+            } else {
+                node.pass_pending = true;
+            }
+        }
+        node
+    }
+
+    fn fully_expanded(&self) -> bool {
+        self.untried == 0 && !self.pass_pending
+    }
+}
+
+// 1スレッド分の探索木 (ノードは配列で管理し、0番を根とする)
+struct Tree {
+    nodes: Vec<Node>,
+}
+
+impl Tree {
+    fn new(board: &Board) -> Tree {
+        Tree { nodes: vec![Node::new(board.clone(), 0)] }
+    }
+
+    // 前回の木の2手先までに同じ局面があれば、その部分木を新しい根として取り出す
+    fn reuse(self, board: &Board) -> Tree {
+        match self.find(board) {
+            Some(0) => self,
+            Some(idx) => self.extract(idx),
+            None => Tree::new(board),
+        }
+    }
+
+    fn find(&self, board: &Board) -> Option<usize> {
+        let same = |idx: usize| {
+            let b = &self.nodes[idx].board;
+            b.player() == board.player() && b.opponent() == board.opponent()
+        };
+        if same(0) {
+            return Some(0);
+        }
+        for &child in self.nodes[0].children.iter() {
+            if same(child) {
+                return Some(child);
+            }
+            for &grandchild in self.nodes[child].children.iter() {
+                if same(grandchild) {
+                    return Some(grandchild);
+                }
+            }
+        }
+        None
+    }
+
+    // idxを根とする部分木を新しい配列に詰め直す (移すノードは取り出すだけで作り直さない)
+    fn extract(self, idx: usize) -> Tree {
+        let mut old: Vec<Option<Node>> = self.nodes.into_iter().map(Some).collect();
+        let mut nodes = Vec::new();
+        let mut stack: Vec<(usize, Option<usize>)> = vec![(idx, None)];
+        while let Some((i, parent)) = stack.pop() {
+            let mut node = old[i].take().expect("MCTS node visited twice");
+            let children = std::mem::take(&mut node.children);
+            let new_idx = nodes.len();
+            nodes.push(node);
+            if let Some(p) = parent {
+                nodes[p].children.push(new_idx);
+            }
+            for &c in children.iter().rev() {
+                stack.push((c, Some(new_idx)));
+            }
+        }
+        Tree { nodes }
+    }
+
+    // UCB1が最大の子を選ぶ
+    fn select_child(&self, idx: usize, exploration: f64) -> usize {
+        let node = &self.nodes[idx];
+        let log_n = (node.visits.max(1) as f64).ln();
+        let mut best = node.children[0];
+        let mut best_ucb = f64::NEG_INFINITY;
+        for &c in node.children.iter() {
+            let child = &self.nodes[c];
+            let ucb = if child.visits == 0 {
+                f64::INFINITY
+            } else {
+                child.wins / child.visits as f64 + exploration * (log_n / child.visits as f64).sqrt()
+            };
+            if ucb > best_ucb {
+                best_ucb = ucb;
+                best = c;
+            }
+        }
+        best
+    }
+
+    // 未展開の手を一つ選んで子ノードを作る
+    fn expand(&mut self, idx: usize, rng: &mut StdRng) -> usize {
+        let node = &mut self.nodes[idx];
+        let mut board = node.board.clone();
+        let mv = if node.pass_pending {
+            node.pass_pending = false;
+            0
+        } else {
+            let n = node.untried.count_ones();
+            let mv = nth_bit(node.untried, rng.gen_range(0..n));
+            node.untried ^= mv;
+            let (_, hints) = board.legals();
+            board.next(mv, hints);
+            mv
+        };
+        board.exchange();
+        let child = self.nodes.len();
+        self.nodes.push(Node::new(board, mv));
+        self.nodes[idx].children.push(child);
+        child
+    }
+
+    // ノード数がmax_nodesに達したら木はそれ以上広げず、葉からのプレイアウトだけを続ける
+    fn iterate(&mut self, exploration: f64, max_nodes: usize, rng: &mut StdRng) {
+        let mut path = vec![0];
+        let mut idx = 0;
+        while !self.nodes[idx].terminal && self.nodes[idx].fully_expanded() {
+            idx = self.select_child(idx, exploration);
+            path.push(idx);
+        }
+        if !self.nodes[idx].terminal && self.nodes.len() < max_nodes {
+            idx = self.expand(idx, rng);
+            path.push(idx);
+        }
+        // resultはidxの手番側から見た結果
+        let mut result = playout(&self.nodes[idx].board, rng);
+        for &i in path.iter().rev() {
+            let node = &mut self.nodes[i];
+            node.visits += 1;
+            node.wins += 1.0 - result;
+            result = 1.0 - result;
+        }
+    }
+}
+
+// maskの中でn番目(0始まり)に立っているビットを返す
+fn nth_bit(mut mask: u64, n: u32) -> u64 {
+    for _ in 0..n {
+        mask &= mask - 1;
+    }
+    mask & mask.wrapping_neg()
+}
+
+/// ランダムプレイアウト。隅が打てるときは必ず隅を取る程度の軽い偏りをつける
+/// 返り値は最初の盤面の手番側から見た結果 (勝ち1.0, 引き分け0.5, 負け0.0)
+fn playout(board: &Board, rng: &mut StdRng) -> f64 {
+    let side = board.side();
+    let mut board = board.clone();
+    let mut pass = false;
+    loop {
+        let (legals, hints) = board.legals();
+        if legals == 0 {
+            if pass {
+                break;
+            }
+            pass = true;
+        } else {
+            pass = false;
+            let corners = legals & CORNERS;
+            let candidates = if corners != 0 { corners } else { legals };
+            let mv = nth_bit(candidates, rng.gen_range(0..candidates.count_ones()));
+            board.next(mv, hints);
+        }
+        board.exchange();
+    }
+    let (me, op) = if board.side() == side {
+        (board.player(), board.opponent())
+    } else {
+        (board.opponent(), board.player())
+    };
+    match me.count_ones().cmp(&op.count_ones()) {
+        std::cmp::Ordering::Greater => 1.0,
+        std::cmp::Ordering::Less => 0.0,
+        std::cmp::Ordering::Equal => 0.5,
+    }
+}
+
+/// UCTによるモンテカルロ木探索
+/// スレッドごとに独立した木を持ち (ルート並列化)、根の訪問回数を合計して手を決める
+/// 木は次の手番まで保持し、2手先までに現在の局面があれば再利用する
+/// 一本の木のノード数はmax_nodesまでに抑える (1ノードは100バイト余り)
+pub struct Mcts {
+    pub threads: usize,
+    pub time_limit: Duration,
+    pub exploration: f64,
+    pub max_nodes: usize,
+    trees: Vec<Tree>,
+}
+
+impl Mcts {
+    pub fn new(threads: usize, time_limit: Duration, max_nodes: usize) -> Mcts {
+        Mcts {
+            threads: threads.max(1),
+            time_limit,
+            exploration: std::f64::consts::SQRT_2,
+            max_nodes: max_nodes.max(1),
+            trees: Vec::new(),
+        }
+    }
+
+    /// 保持している木を捨てる (新しい対局の開始時など)
+    pub fn clear(&mut self) {
+        self.trees.clear();
+    }
+
+    /// time_limitと中断フラグのどちらかが来るまで探索し、最も訪問回数の多い手を返す
+    /// 合法手がなければ0 (パス)
+    pub fn search(&mut self, board: &Board, ctx: &mut SearchContext) -> u64 {
+        let (legals, _) = board.legals();
+        if legals == 0 {
+            return 0;
+        }
+        let old = std::mem::take(&mut self.trees);
+        let mut trees: Vec<Tree> = old.into_iter().map(|t| t.reuse(board)).collect();
+        trees.resize_with(self.threads, || Tree::new(board));
+        let seeds: Vec<u64> = trees.iter().map(|_| ctx.rng.gen()).collect();
+
+        let deadline = Instant::now() + self.time_limit;
+        let exploration = self.exploration;
+        let max_nodes = self.max_nodes;
+        let stop = &ctx.stop;
+        thread::scope(|s| {
+            for (tree, &seed) in trees.iter_mut().zip(seeds.iter()) {
+                s.spawn(move || {
+                    let mut rng = StdRng::seed_from_u64(seed);
+                    loop {
+                        for _ in 0..64 {
+                            tree.iterate(exploration, max_nodes, &mut rng);
+                        }
+                        if stop.poll() || Instant::now() >= deadline {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+
+        // 各スレッドの根の子の訪問回数を手ごとに合計する
+        let mut visits = [0u64; 64];
+        let mut playouts = 0;
+        for tree in trees.iter() {
+            playouts += tree.nodes[0].visits;
+            for &c in tree.nodes[0].children.iter() {
+                let child = &tree.nodes[c];
+                visits[child.mv.trailing_zeros() as usize] += child.visits as u64;
+            }
+        }
+        let nodes: usize = trees.iter().map(|t| t.nodes.len()).sum();
+        eprintln!("[Info] MCTS playouts: {}, nodes: {}", playouts, nodes);
+        self.trees = trees;
+        let best = (0..64).max_by_key(|&i| visits[i]).unwrap();
+        if visits[best] == 0 {
+            legals & legals.wrapping_neg()
+        } else {
+            1 << best
+        }
+    }
+}
@@ -0,0 +1,515 @@
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::bitboard::{Board, Undo};
+use crate::cmds::Color;
+use crate::dataset::Sample;
+use crate::evaluator::Evaluator;
+use crate::score;
+use crate::symmetry::Symmetry;
+use crate::tuner;
+
+// 入力は手番側から見た 自分の石 (64) と相手の石 (64)
+const INPUTS: usize = 128;
+const HIDDEN: usize = 32;
+// 量子化の倍率。隠れ層の活性1.0をQA、出力層の重み1.0をQBで表す
+const QA: i32 = 127;
+const QB: i32 = 64;
+// 出力1.0を石差64とし、評価値は石差の1/100の単位 (`score::DISC`) にする
+const OUTPUT_SCALE: i64 = 64 * score::DISC as i64;
+const TARGET_SCALE: f32 = 64.0;
+
+/// 盤面の石から評価値を出す小さなネットワーク (入力128 → 隠れ層32 (clipped ReLU) → 出力1)
+/// 重みは整数に量子化してあり、隠れ層の手前 (アキュムレータ) は手を打つたびに差分で更新できる
+pub struct Network {
+    input_bias: [i16; HIDDEN],
+    input_weights: Vec<[i16; HIDDEN]>,
+    output_weights: [i16; HIDDEN],
+    // QA * QB倍した出力のバイアス
+    output_bias: i32,
+    // 出力に掛ける倍率 (`score::SCALE_ONE`で1倍)。学習の後に終局時の石差に合わせて求める
+    scale: i32,
+}
+
+/// 隠れ層の手前の値。黒から見たものと白から見たものを両方持ち、手番側の方を使う
+/// 重みはi16だが、64マス分を足すとi16に収まらないことがあるのでi32で持つ
+#[derive(Clone)]
+pub struct Accumulator {
+    black: u64,
+    white: u64,
+    values: [[i32; HIDDEN]; 2],
+}
+
+// 色ごとのアキュムレータの添字
+#[inline]
+fn perspective(color: Color) -> usize {
+    if color == Color::Black { 0 } else { 1 }
+}
+
+#[inline]
+fn add(values: &mut [i32; HIDDEN], weights: &[i16; HIDDEN]) {
+    for (v, w) in values.iter_mut().zip(weights) {
+        *v += *w as i32;
+    }
+}
+
+#[inline]
+fn sub(values: &mut [i32; HIDDEN], weights: &[i16; HIDDEN]) {
+    for (v, w) in values.iter_mut().zip(weights) {
+        *v -= *w as i32;
+    }
+}
+
+impl Network {
+    /// 盤面から作り直す
+    pub fn refresh(&self, black: u64, white: u64) -> Accumulator {
+        let mut values = [self.input_bias.map(i32::from); 2];
+        for (color, own, other) in [(0, black, white), (1, white, black)] {
+            let mut bits = own;
+            while bits != 0 {
+                add(&mut values[color], &self.input_weights[bits.trailing_zeros() as usize]);
+                bits &= bits - 1;
+            }
+            let mut bits = other;
+            while bits != 0 {
+                add(&mut values[color], &self.input_weights[64 + bits.trailing_zeros() as usize]);
+                bits &= bits - 1;
+            }
+        }
+        Accumulator { black, white, values }
+    }
+
+    /// 親の局面のアキュムレータに、moverが打った手 (undo) の差分を足す
+    pub fn update(&self, parent: &Accumulator, mover: Color, undo: &Undo, black: u64, white: u64) -> Accumulator {
+        let mut values = parent.values;
+        let (own, other) = (perspective(mover), 1 - perspective(mover));
+        let mv = undo.mv.trailing_zeros() as usize;
+        add(&mut values[own], &self.input_weights[mv]);
+        add(&mut values[other], &self.input_weights[64 + mv]);
+        let mut flipped = undo.flipped;
+        while flipped != 0 {
+            let sq = flipped.trailing_zeros() as usize;
+            add(&mut values[own], &self.input_weights[sq]);
+            sub(&mut values[own], &self.input_weights[64 + sq]);
+            add(&mut values[other], &self.input_weights[64 + sq]);
+            sub(&mut values[other], &self.input_weights[sq]);
+            flipped &= flipped - 1;
+        }
+        Accumulator { black, white, values }
+    }
+
+    /// side側から見た評価値 (1石 = 100)
+    pub fn output(&self, acc: &Accumulator, side: Color) -> i32 {
+        let hidden = &acc.values[perspective(side)];
+        // 出力層のバイアスはファイルから読んだ任意のi32なので、i64で足す
+        let mut sum = self.output_bias as i64;
+        for (&h, &w) in hidden.iter().zip(self.output_weights.iter()) {
+            sum += (h.clamp(0, QA) * w as i32) as i64;
+        }
+        let value = sum * OUTPUT_SCALE / (QA * QB) as i64;
+        (value.saturating_mul(self.scale as i64) / score::SCALE_ONE as i64).clamp(i32::MIN as i64, i32::MAX as i64) as i32
+    }
+
+    pub fn evaluate(&self, board: &Board) -> i32 {
+        self.output(&self.refresh(board.black(), board.white()), board.side())
+    }
+
+    /// 重みファイルを読む
+    /// 形式は`nnue 128 32`の見出し行と`scale 倍率`の行 (省略すると1倍) の後に、隠れ層のバイアス・入力ごとの重み (128行)・
+    /// 出力層の重み・出力層のバイアスの整数を空白区切りで並べたもの (`#`以降はコメント)。`train`が書き出す
+    pub fn load(path: &Path) -> Result<Network, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let mut numbers = Vec::new();
+        let mut header = false;
+        let mut scale = score::SCALE_ONE;
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("");
+            let error = |msg: String| format!("{}:{}: {}", path.display(), i + 1, msg);
+            if line.trim().is_empty() {
+                continue;
+            }
+            if !header {
+                let expected = format!("nnue {} {}", INPUTS, HIDDEN);
+                if line.split_whitespace().collect::<Vec<_>>().join(" ") != expected {
+                    return Err(error(format!("expected header '{}'", expected)));
+                }
+                header = true;
+                continue;
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if numbers.is_empty() && fields[0] == "scale" {
+                scale = match fields[1..] {
+                    [n] => n.parse().ok().filter(|&n| n > 0).ok_or_else(|| error(format!("invalid scale '{}'", n)))?,
+                    _ => return Err(error("expected 'scale <n>'".to_string())),
+                };
+                continue;
+            }
+            for field in fields {
+                numbers.push(field.parse::<i32>().map_err(|_| error(format!("invalid number '{}'", field)))?);
+            }
+        }
+        let expected = HIDDEN + INPUTS * HIDDEN + HIDDEN + 1;
+        if numbers.len() != expected {
+            return Err(format!("{}: expected {} weights, found {}", path.display(), expected, numbers.len()));
+        }
+        let to_i16 = |n: &i32| i16::try_from(*n).map_err(|_| format!("{}: weight {} out of range", path.display(), n));
+        let row = |chunk: &[i32]| -> Result<[i16; HIDDEN], String> {
+            let mut row = [0; HIDDEN];
+            for (r, n) in row.iter_mut().zip(chunk) {
+                *r = to_i16(n)?;
+            }
+            Ok(row)
+        };
+        let mut chunks = numbers.chunks(HIDDEN);
+        let input_bias = row(chunks.next().unwrap())?;
+        let input_weights = (0..INPUTS).map(|_| row(chunks.next().unwrap())).collect::<Result<Vec<_>, _>>()?;
+        let output_weights = row(chunks.next().unwrap())?;
+        let output_bias = chunks.next().unwrap()[0];
+        Ok(Network { input_bias, input_weights, output_weights, output_bias, scale })
+    }
+
+    fn save(&self, path: &Path) -> Result<(), String> {
+        let row = |r: &[i16; HIDDEN]| r.iter().map(|w| w.to_string()).collect::<Vec<_>>().join(" ");
+        let mut s = format!("nnue {} {}\nscale {}\n# input bias\n{}\n# input weights (own discs A1..H8, then opponent discs)\n", INPUTS, HIDDEN, self.scale, row(&self.input_bias));
+        for r in self.input_weights.iter() {
+            s += &row(r);
+            s.push('\n');
+        }
+        s += &format!("# output weights\n{}\n# output bias\n{}\n", row(&self.output_weights), self.output_bias);
+        fs::write(path, s).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+}
+
+/// ネットワークを使う評価関数
+pub struct Nnue {
+    network: Arc<Network>,
+}
+
+impl Nnue {
+    pub fn load(path: &Path) -> Result<Nnue, String> {
+        Ok(Nnue { network: Arc::new(Network::load(path)?) })
+    }
+}
+
+impl Evaluator for Nnue {
+    fn name(&self) -> &str {
+        "nnue"
+    }
+
+    fn evaluate(&self, board: &Board, _player_moves: u64, _opponent_moves: u64) -> i32 {
+        self.network.evaluate(board)
+    }
+
+    fn scale(&self) -> i32 {
+        self.network.scale
+    }
+
+    fn network(&self) -> Option<Arc<Network>> {
+        Some(self.network.clone())
+    }
+}
+
+/// 探索中のアキュムレータを手順に沿って積んでおく。手を打つたびにpush、戻すたびにpopする
+/// 積んであるものが今の局面と合わなければ (探索の根など) 盤面から作り直すので、pushし忘れても値は正しい
+pub struct AccumulatorStack {
+    network: Option<Arc<Network>>,
+    stack: Vec<Accumulator>,
+}
+
+impl AccumulatorStack {
+    pub fn new(network: Option<Arc<Network>>) -> AccumulatorStack {
+        AccumulatorStack { network, stack: Vec::with_capacity(128) }
+    }
+
+    /// make_moveで進めた直後の局面boardのアキュムレータを積む
+    #[inline]
+    pub fn push(&mut self, board: &Board, undo: &Undo) {
+        let network = match &self.network {
+            Some(network) => network,
+            None => return,
+        };
+        let (black, white) = (board.black(), board.white());
+        // 手を打ったのは今の手番の相手
+        let mover = if board.side() == Color::Black { Color::White } else { Color::Black };
+        let (mut parent_black, mut parent_white) = (black, white);
+        if mover == Color::Black {
+            parent_black &= !(undo.mv | undo.flipped);
+            parent_white |= undo.flipped;
+        } else {
+            parent_white &= !(undo.mv | undo.flipped);
+            parent_black |= undo.flipped;
+        }
+        let acc = match self.stack.last() {
+            Some(parent) if parent.black == parent_black && parent.white == parent_white => {
+                network.update(parent, mover, undo, black, white)
+            }
+            _ => network.refresh(black, white),
+        };
+        self.stack.push(acc);
+    }
+
+    #[inline]
+    pub fn pop(&mut self) {
+        if self.network.is_some() {
+            self.stack.pop();
+        }
+    }
+
+    /// ネットワークで局面を評価する。ネットワークを使わない評価関数ならNone
+    #[inline]
+    pub fn evaluate(&self, board: &Board) -> Option<i32> {
+        let network = self.network.as_ref()?;
+        Some(match self.stack.last() {
+            Some(acc) if acc.black == board.black() && acc.white == board.white() => {
+                debug_assert_eq!(acc.values, network.refresh(acc.black, acc.white).values);
+                network.output(acc, board.side())
+            }
+            _ => network.evaluate(board),
+        })
+    }
+}
+
+// 学習中の浮動小数点の重み
+struct FloatNetwork {
+    input_bias: [f32; HIDDEN],
+    input_weights: Vec<[f32; HIDDEN]>,
+    output_weights: [f32; HIDDEN],
+    output_bias: f32,
+}
+
+// 手番側から見た入力 (値が1の入力の番号)
+fn features(board: &Board, inputs: &mut Vec<usize>) {
+    inputs.clear();
+    for (offset, bits) in [(0, board.player()), (64, board.opponent())] {
+        let mut bits = bits;
+        while bits != 0 {
+            inputs.push(offset + bits.trailing_zeros() as usize);
+            bits &= bits - 1;
+        }
+    }
+}
+
+impl FloatNetwork {
+    fn new(rng: &mut StdRng) -> FloatNetwork {
+        let mut random_row = || {
+            let mut row = [0.0; HIDDEN];
+            row.iter_mut().for_each(|w| *w = rng.gen_range(-0.1..0.1));
+            row
+        };
+        FloatNetwork {
+            input_bias: [0.5; HIDDEN],
+            input_weights: (0..INPUTS).map(|_| random_row()).collect(),
+            output_weights: random_row(),
+            output_bias: 0.0,
+        }
+    }
+
+    // 隠れ層の手前の値
+    fn hidden(&self, features: &[usize]) -> [f32; HIDDEN] {
+        let mut hidden = self.input_bias;
+        for &f in features {
+            for (h, w) in hidden.iter_mut().zip(self.input_weights[f].iter()) {
+                *h += w;
+            }
+        }
+        hidden
+    }
+
+    fn forward(&self, hidden: &[f32; HIDDEN]) -> f32 {
+        let activated = hidden.iter().map(|h| h.clamp(0.0, 1.0));
+        self.output_bias + activated.zip(self.output_weights.iter()).map(|(a, w)| a * w).sum::<f32>()
+    }
+
+    // 一局面分の二乗誤差で重みを更新し、更新前の出力を返す
+    fn step(&mut self, features: &[usize], target: f32, lr: f32) -> f32 {
+        let hidden = self.hidden(features);
+        let output = self.forward(&hidden);
+        let grad = 2.0 * (output - target);
+        let mut hidden_grad = [0.0; HIDDEN];
+        for i in 0..HIDDEN {
+            let h = hidden[i];
+            if h > 0.0 && h < 1.0 {
+                hidden_grad[i] = grad * self.output_weights[i];
+            }
+            self.output_weights[i] -= lr * grad * h.clamp(0.0, 1.0);
+        }
+        self.output_bias -= lr * grad;
+        for (b, g) in self.input_bias.iter_mut().zip(hidden_grad.iter()) {
+            *b -= lr * g;
+        }
+        for &f in features {
+            for (w, g) in self.input_weights[f].iter_mut().zip(hidden_grad.iter()) {
+                *w -= lr * g;
+            }
+        }
+        output
+    }
+
+    fn quantize(&self) -> Network {
+        let row = |r: &[f32; HIDDEN], scale: i32| {
+            let mut q = [0i16; HIDDEN];
+            for (q, w) in q.iter_mut().zip(r) {
+                *q = (w * scale as f32).round().clamp(i16::MIN as f32, i16::MAX as f32) as i16;
+            }
+            q
+        };
+        Network {
+            input_bias: row(&self.input_bias, QA),
+            input_weights: self.input_weights.iter().map(|r| row(r, QA)).collect(),
+            output_weights: row(&self.output_weights, QB),
+            output_bias: (self.output_bias * (QA * QB) as f32).round() as i32,
+            scale: score::SCALE_ONE,
+        }
+    }
+}
+
+// 評価値 (1石 = 100) と目標の石差の二乗平均平方根 (石)
+fn rms(network: &Network, samples: &[&Sample]) -> f64 {
+    if samples.is_empty() {
+        return 0.0;
+    }
+    let sum: f64 = samples
+        .iter()
+        .map(|s| (network.evaluate(&s.board) as f64 / 100.0 - s.target as f64).powi(2))
+        .sum();
+    (sum / samples.len() as f64).sqrt()
+}
+
+/// 局面と石差の組からネットワークを学習し、量子化した重みをoutに書き出す
+/// 局面は毎回ランダムに回転・反転させて使い、一割を検証用に取っておく
+/// 最後に出力の倍率を学習用の局面の石差に合わせる (`tuner::fit_scale`)
+pub fn train(samples: &[Sample], out: &Path, epochs: usize, lr: f32, seed: u64) -> Result<(), String> {
+    if samples.is_empty() {
+        return Err("no training positions".to_string());
+    }
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut order: Vec<&Sample> = samples.iter().collect();
+    for i in (1..order.len()).rev() {
+        order.swap(i, rng.gen_range(0..=i));
+    }
+    let (validation, training) = order.split_at(order.len() / 10);
+    let mut training = training.to_vec();
+    println!("Training positions: {}, validation positions: {}", training.len(), validation.len());
+
+    let mut network = FloatNetwork::new(&mut rng);
+    let mut inputs = Vec::with_capacity(64);
+    for epoch in 1..=epochs {
+        for i in (1..training.len()).rev() {
+            training.swap(i, rng.gen_range(0..=i));
+        }
+        let mut sum = 0.0;
+        for sample in training.iter() {
+            let sym = Symmetry::ALL[rng.gen_range(0..Symmetry::ALL.len())];
+            features(&sample.board.transform(sym), &mut inputs);
+            let target = sample.target / TARGET_SCALE;
+            let output = network.step(&inputs, target, lr);
+            sum += ((output - target) * TARGET_SCALE).powi(2) as f64;
+        }
+        let train_rms = (sum / training.len() as f64).sqrt();
+        println!(
+            "epoch {}: training rms {:.2} discs, validation rms {:.2} discs",
+            epoch,
+            train_rms,
+            rms(&network.quantize(), validation)
+        );
+    }
+    let mut network = network.quantize();
+    network.scale = tuner::fit_scale(training.iter().map(|s| (network.evaluate(&s.board), s.target)), network.scale);
+    println!("scale {} (validation rms {:.2} discs)", network.scale, rms(&network, validation));
+    network.save(out)?;
+    println!("Wrote {}", out.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::Game;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("reversi-test-{}-{}", std::process::id(), name))
+    }
+
+    fn network(seed: u64) -> Network {
+        FloatNetwork::new(&mut StdRng::seed_from_u64(seed)).quantize()
+    }
+
+    #[test]
+    fn save_and_load_round_trip() {
+        let path = temp_path("nnue-round-trip.txt");
+        let mut original = network(1);
+        original.scale = 900;
+        original.save(&path).unwrap();
+        let loaded = Network::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.input_bias, original.input_bias);
+        assert_eq!(loaded.input_weights, original.input_weights);
+        assert_eq!(loaded.output_weights, original.output_weights);
+        assert_eq!(loaded.output_bias, original.output_bias);
+        assert_eq!(loaded.scale, original.scale);
+    }
+
+    #[test]
+    fn load_rejects_invalid_files() {
+        let path = temp_path("nnue-invalid.txt");
+        let weights = |n: usize, value: &str| vec![value; n].join(" ");
+        let count = HIDDEN + INPUTS * HIDDEN + HIDDEN + 1;
+        for text in [
+            format!("nnue 128 16\n{}\n", weights(count, "0")),
+            format!("nnue 128 32\n{}\n", weights(count - 1, "0")),
+            format!("nnue 128 32\n{} x\n", weights(count - 1, "0")),
+            format!("nnue 128 32\n40000 {}\n", weights(count - 1, "0")),
+            format!("nnue 128 32\nscale 0\n{}\n", weights(count, "0")),
+            format!("nnue 128 32\nscale\n{}\n", weights(count, "0")),
+            format!("nnue 128 32\n{}\nscale 1024\n", weights(count, "0")),
+        ] {
+            fs::write(&path, text).unwrap();
+            assert!(Network::load(&path).is_err());
+        }
+        fs::write(&path, format!("nnue 128 32\n{}\n", weights(count, "0"))).unwrap();
+        assert!(Network::load(&path).is_ok());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn large_weights_do_not_overflow() {
+        // 自分の石の重みがどれもi16の上限に近いと、石を足した合計はi16に収まらない
+        let mut network = network(2);
+        network.input_bias = [0; HIDDEN];
+        for row in network.input_weights.iter_mut().take(64) {
+            *row = [i16::MAX; HIDDEN];
+        }
+        network.output_weights = [QB as i16; HIDDEN];
+        network.output_bias = 0;
+        let acc = network.refresh(u64::MAX, 0);
+        assert!(acc.values[0].iter().all(|&v| v == 64 * i16::MAX as i32));
+        // 隠れ層はすべて上限 (1.0) に張り付き、出力は1.0 * HIDDEN
+        assert_eq!(network.output(&acc, Color::Black), (HIDDEN as i64 * OUTPUT_SCALE) as i32);
+    }
+
+    #[test]
+    fn scale_multiplies_the_output() {
+        let mut network = network(4);
+        let board = Board::new();
+        let one = network.evaluate(&board);
+        network.scale = 3 * score::SCALE_ONE;
+        assert_eq!(network.evaluate(&board), one * 3);
+    }
+
+    #[test]
+    fn incremental_update_matches_refresh() {
+        let network = network(3);
+        let mut board = Board::new();
+        let mut acc = network.refresh(board.black(), board.white());
+        for mv in Game::from_transcript("f5d6c3d3c4f4f6f3e6e7").unwrap().moves() {
+            let mover = board.side();
+            let (_, hints) = board.legals();
+            let undo = board.make_move(mv, hints);
+            acc = network.update(&acc, mover, &undo, board.black(), board.white());
+            assert_eq!(acc.values, network.refresh(board.black(), board.white()).values);
+        }
+    }
+}
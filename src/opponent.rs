@@ -0,0 +1,247 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+
+use crate::bitboard::Board;
+use crate::score;
+use crate::search::{alpha_beta, SearchContext};
+
+// 相手の手を採点するときの探索の深さ
+const PROBE_DEPTH: usize = 2;
+// 一手あたりの評価値の差の上限 (読み切った勝ち・負けで評価値が振り切れるのを防ぐ)
+const MAX_LOSS: i64 = 30 * score::DISC as i64;
+// 判定に必要な観測手数 (合法手が一つしかない手は数えない)
+const MIN_MOVES: u32 = 10;
+// 相手の損失がランダムに打った場合の期待値のこの割合を超えたら弱い相手とみなす
+const WEAK_RATIO: f64 = 0.7;
+
+/// 対戦相手ごとに蓄積する統計 (勝敗は自分から見たもの)
+#[derive(Clone, Copy, Default, Debug)]
+pub struct OpponentStats {
+    pub games: u32,
+    pub wins: u32,
+    pub losses: u32,
+    pub draws: u32,
+    /// 観測した相手の手の数
+    pub moves: u32,
+    /// 自分の探索の最善手と一致した回数
+    pub agreements: u32,
+    /// 最善手との評価値の差の合計 (小さいほど良い手を打っている)
+    pub total_loss: i64,
+    /// 同じ局面でランダムに打った場合の差の期待値の合計
+    pub random_loss: i64,
+}
+
+impl OpponentStats {
+    /// ランダムな相手を1.0としたときの相手の手の悪さ
+    pub fn loss_ratio(&self) -> f64 {
+        if self.random_loss <= 0 {
+            0.0
+        } else {
+            self.total_loss as f64 / self.random_loss as f64
+        }
+    }
+
+    pub fn agreement_rate(&self) -> f64 {
+        if self.moves == 0 {
+            0.0
+        } else {
+            self.agreements as f64 / self.moves as f64
+        }
+    }
+
+    /// 十分な手数を観測していて、ランダムに近い打ち方をしているか
+    pub fn is_weak(&self) -> bool {
+        self.moves >= MIN_MOVES && self.loss_ratio() > WEAK_RATIO
+    }
+
+    fn add_moves(&mut self, other: &OpponentStats) {
+        self.moves += other.moves;
+        self.agreements += other.agreements;
+        self.total_loss += other.total_loss;
+        self.random_loss += other.random_loss;
+    }
+}
+
+/// 対局結果 (自分から見たもの)
+pub enum Outcome {
+    Win,
+    Lose,
+    Draw,
+}
+
+/// 相手の名前ごとの統計をファイルに保存しつつ、対局中の相手の強さを判定する
+pub struct OpponentModel {
+    path: PathBuf,
+    db: HashMap<String, OpponentStats>,
+    name: String,
+    current: OpponentStats,
+    weak: bool,
+    // 相手の手の採点専用の探索文脈 (自分の探索の乱数や中断フラグに影響を与えないため)
+    ctx: SearchContext,
+}
+
+impl OpponentModel {
+    /// 統計ファイルを読み込む。ファイルがなければ空から始める
+    /// 形式は一行に一人で `名前 games wins losses draws moves agreements total_loss random_loss`
+    /// (名前は空白を含まず`#`で始まらないもの。そうでない名前は`save`で書き出さない)
+    pub fn load(path: PathBuf) -> OpponentModel {
+        let mut db = HashMap::new();
+        if let Ok(text) = fs::read_to_string(&path) {
+            for line in text.lines() {
+                let fields: Vec<&str> = line.split_whitespace().collect();
+                if fields.is_empty() || fields[0].starts_with('#') {
+                    continue;
+                }
+                match parse_stats(&fields) {
+                    Some(stats) => {
+                        db.insert(fields[0].to_string(), stats);
+                    }
+                    None => eprintln!("[Warn] Invalid opponent record: {}", line),
+                }
+            }
+        }
+        OpponentModel {
+            path,
+            db,
+            name: String::new(),
+            current: OpponentStats::default(),
+            weak: false,
+            ctx: SearchContext::new(0),
+        }
+    }
+
+    pub fn save(&self) {
+        let mut names: Vec<&String> = self.db.keys().collect();
+        names.sort();
+        let mut text = String::from("# name games wins losses draws moves agreements total_loss random_loss\n");
+        for name in names {
+            if !is_storable(name) {
+                eprintln!("[Warn] Not saving stats of opponent {:?}: the name is empty, contains whitespace or starts with '#'", name);
+                continue;
+            }
+            let s = &self.db[name];
+            text += &format!(
+                "{} {} {} {} {} {} {} {} {}\n",
+                name, s.games, s.wins, s.losses, s.draws, s.moves, s.agreements, s.total_loss, s.random_loss
+            );
+        }
+        if let Err(e) = fs::write(&self.path, text) {
+            eprintln!("[Warn] Failed to save opponent stats to {}: {}", self.path.display(), e);
+        }
+    }
+
+    /// 新しい対局を始める。過去の対局ですでに弱いと分かっている相手なら最初から速指しにする
+    pub fn start_game(&mut self, name: &str) {
+        self.name = name.to_string();
+        self.current = OpponentStats::default();
+        let past = self.db.get(name).cloned().unwrap_or_default();
+        self.weak = past.is_weak();
+        if past.games > 0 {
+            println!(
+                "Opponent {}: {} games (Win {}, Lose {}, Draw {}), agreement {:.0}%, loss ratio {:.2}{}",
+                name,
+                past.games,
+                past.wins,
+                past.losses,
+                past.draws,
+                past.agreement_rate() * 100.0,
+                past.loss_ratio(),
+                if self.weak { " -> fast play" } else { "" }
+            );
+        }
+    }
+
+    /// 現在の対局相手の名前
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// 相手が弱い(ランダムに近い)と判定されているか
+    pub fn is_weak(&self) -> bool {
+        self.weak
+    }
+
+    /// 相手が盤面boardでmvを打ったことを記録する (boardは相手の手番の盤面、mv=0はパス)
+    /// 合法手それぞれを浅く探索し、最善手との差を相手の手の悪さとして蓄積する
+    pub fn observe(&mut self, board: &Board, mv: u64) {
+        let (legals, hints) = board.legals();
+        if legals.count_ones() < 2 || legals & mv == 0 {
+            return;
+        }
+        let mut scores = Vec::new();
+        let mut chosen = 0;
+        let mut root = board.clone();
+        for i in 0..64 {
+            let m = 1u64 << i;
+            if legals & m == 0 {
+                continue;
+            }
+            let undo = root.make_move(m, hints);
+            let score = -(alpha_beta(&mut root, -score::INFINITY, score::INFINITY, PROBE_DEPTH - 1, false, &mut self.ctx) as i64);
+            root.unmake_move(undo);
+            if m == mv {
+                chosen = score;
+            }
+            scores.push(score);
+        }
+        let best = *scores.iter().max().unwrap();
+        let mean_loss = scores.iter().map(|&s| (best - s).min(MAX_LOSS)).sum::<i64>() / scores.len() as i64;
+
+        self.current.moves += 1;
+        if chosen == best {
+            self.current.agreements += 1;
+        }
+        self.current.total_loss += (best - chosen).min(MAX_LOSS);
+        self.current.random_loss += mean_loss;
+
+        // 過去の対局と今回の対局の観測を合わせて判定する
+        let mut total = self.db.get(&self.name).cloned().unwrap_or_default();
+        total.add_moves(&self.current);
+        if !self.weak && total.is_weak() {
+            eprintln!(
+                "[Info] Opponent {} looks weak (agreement {:.0}%, loss ratio {:.2}), switching to fast play",
+                self.name,
+                total.agreement_rate() * 100.0,
+                total.loss_ratio()
+            );
+            self.weak = true;
+        }
+    }
+
+    /// 対局の結果を記録してファイルに保存する
+    pub fn finish_game(&mut self, outcome: Outcome) {
+        let current = self.current;
+        let stats = self.db.entry(self.name.clone()).or_default();
+        stats.games += 1;
+        match outcome {
+            Outcome::Win => stats.wins += 1,
+            Outcome::Lose => stats.losses += 1,
+            Outcome::Draw => stats.draws += 1,
+        }
+        stats.add_moves(&current);
+        self.current = OpponentStats::default();
+        self.save();
+    }
+}
+
+// 統計ファイルの一行に書いて読み戻せる名前か
+fn is_storable(name: &str) -> bool {
+    !name.is_empty() && !name.starts_with('#') && !name.contains(char::is_whitespace)
+}
+
+fn parse_stats(fields: &[&str]) -> Option<OpponentStats> {
+    if fields.len() != 9 {
+        return None;
+    }
+    Some(OpponentStats {
+        games: fields[1].parse().ok()?,
+        wins: fields[2].parse().ok()?,
+        losses: fields[3].parse().ok()?,
+        draws: fields[4].parse().ok()?,
+        moves: fields[5].parse().ok()?,
+        agreements: fields[6].parse().ok()?,
+        total_loss: fields[7].parse().ok()?,
+        random_loss: fields[8].parse().ok()?,
+    })
+}
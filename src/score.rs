@@ -0,0 +1,73 @@
+// 評価値の単位と、読み切った勝ち・負けの表し方
+// 評価値は手番側から見た石差の1/100 (1石 = DISC)。評価関数の値も探索の値もこの単位にそろえる
+// 読み切った勝ち・負けは普通の評価値の範囲の外側に置き、勝った (負けた) 石差も持たせる
+
+/// 評価値 (手番側から見た値)
+pub type Score = i32;
+
+/// 1石分の評価値
+pub const DISC: Score = 100;
+/// 読み切った勝ちの下限。d石差の勝ちは WIN + d、負けは -(WIN + d)、引き分けは0
+/// 勝敗だけを調べる窓で探索したときのWINちょうどは、石差の分からない勝ちを表す
+/// 普通の評価値はこれより小さい範囲に収める
+pub const WIN: Score = 1_000_000;
+/// 探索の窓の端 (どの評価値よりも大きい)
+pub const INFINITY: Score = 2_000_000;
+
+/// 評価値を勝率に直すときの傾き。1石差あたり約0.52で、5石リードしていれば勝率およそ9割
+/// READMEの`games.txt`で、倍率を合わせたheuristicの評価値と勝敗の二乗誤差が最小になる値 (`calibrate`)
+pub const K: f64 = 0.0052;
+
+/// 評価関数の出力の倍率で1倍を表す値
+/// 評価関数は重みから計算した値に倍率を掛けて、終局時の石差にそろえた評価値を返す (`calibrate`で倍率を求める)
+pub const SCALE_ONE: i32 = 1024;
+
+/// 評価関数の重みから計算した値rawに倍率scaleを掛ける
+#[inline]
+pub fn scaled(raw: i32, scale: i32) -> Score {
+    (raw as i64 * scale as i64 / SCALE_ONE as i64) as Score
+}
+
+/// 終局した局面の評価値 (手番側の石差から)
+pub fn proven(disc_diff: i32) -> Score {
+    match disc_diff.signum() {
+        1 => WIN + disc_diff,
+        -1 => -(WIN - disc_diff),
+        _ => 0,
+    }
+}
+
+/// 読み切った勝ち・負けか
+pub fn is_proven(score: Score) -> bool {
+    score.abs() >= WIN
+}
+
+/// 評価関数の値を普通の評価値の範囲に収める
+#[inline]
+pub fn clamp(score: Score) -> Score {
+    score.clamp(-(WIN - 1), WIN - 1)
+}
+
+/// 手番側の勝率 (読み切った勝ちは1、負けは0)
+pub fn win_probability(score: Score) -> f64 {
+    if is_proven(score) {
+        if score > 0 { 1.0 } else { 0.0 }
+    } else {
+        1.0 / (1.0 + (-K * score as f64).exp())
+    }
+}
+
+/// 表示用の文字列。普通の評価値は石差 (+1.25)、読み切った結果は `win +12` / `loss -12`
+pub fn format(score: Score) -> String {
+    if is_proven(score) {
+        let margin = score.abs() - WIN;
+        match (score > 0, margin) {
+            (true, 0) => "win".to_string(),
+            (false, 0) => "loss".to_string(),
+            (true, _) => format!("win +{}", margin),
+            (false, _) => format!("loss -{}", margin),
+        }
+    } else {
+        format!("{:+.2}", score as f64 / DISC as f64)
+    }
+}
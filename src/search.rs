@@ -1,9 +1,19 @@
-use rand::random;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::cmp::Reverse;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use crate::bitboard::Board;
+use crate::evalcache::EvalCache;
+use crate::evaluator::{Evaluator, Heuristic};
+use crate::nnue::AccumulatorStack;
+use crate::score::{self, Score};
+use crate::solved::{SolveKind, Solved, SOLVED};
 
 // bookモジュールとグローバルな定石DBをインポート
-use crate::book::OPENING_BOOK;
+use crate::book::opening_book;
 
 // Import transtable
 // use crate::transposition::{TT, TableEntry, NodeType};
@@ -17,10 +27,8 @@ pub fn alpha_beta(board: &Board, alpha: i32, beta: i32, depth: usize, pass: bool
     // 置換表への格納時に比較するため、元のalphaの値を保持
     let original_alpha = alpha;
 
-    let key = (board.get_black(), board.get_white());
-
     // --- 1. 置換表の参照 ---
-    if let Some(entry) = TT.probe(key) {
+    if let Some(entry) = TT.probe(board) {
         if entry.depth >= depth {
             match entry.node_type {
                 NodeType::Exact => return entry.score,
@@ -87,42 +95,343 @@ pub fn alpha_beta(board: &Board, alpha: i32, beta: i32, depth: usize, pass: bool
     } else {
         NodeType::Exact
     };
-    TT.store(key, TableEntry { score: best_score, depth, node_type });
+    TT.store(board, TableEntry { score: best_score, depth, node_type });
 
     best_score
 }
 */
 
+/// 探索の中断フラグ
+/// cloneしたものはフラグも期限も共有するので、UIなど別スレッドから`stop`を呼べば探索が止まる
+/// 期限を設定すれば、それを過ぎた後の`poll`でも止まる (タイマー用のスレッドは立てない)
+#[derive(Clone)]
+pub struct StopFlag {
+    shared: Arc<StopState>,
+}
+
+struct StopState {
+    stopped: AtomicBool,
+    // 期限 (originからのナノ秒、NO_DEADLINEなら期限なし)
+    origin: Instant,
+    deadline: AtomicU64,
+}
+
+const NO_DEADLINE: u64 = u64::MAX;
+
+impl Default for StopFlag {
+    fn default() -> Self {
+        StopFlag {
+            shared: Arc::new(StopState {
+                stopped: AtomicBool::new(false),
+                origin: Instant::now(),
+                deadline: AtomicU64::new(NO_DEADLINE),
+            }),
+        }
+    }
+}
+
+impl StopFlag {
+    pub fn new() -> Self {
+        StopFlag::default()
+    }
+
+    pub fn stop(&self) {
+        self.shared.stopped.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.shared.stopped.load(Ordering::Relaxed)
+    }
+
+    /// 期限を過ぎていればフラグを立てる。止まっているかどうかを返す
+    /// 時計を読むのは安くないので、探索では何局面かおきに呼ぶ
+    pub fn poll(&self) -> bool {
+        let deadline = self.shared.deadline.load(Ordering::Relaxed);
+        if !self.is_stopped() && deadline != NO_DEADLINE && self.elapsed() >= deadline {
+            self.stop();
+        }
+        self.is_stopped()
+    }
+
+    /// フラグを下ろし、期限も消す。一手ごとの探索の前に呼ぶ
+    pub fn reset(&self) {
+        self.shared.deadline.store(NO_DEADLINE, Ordering::Relaxed);
+        self.shared.stopped.store(false, Ordering::Relaxed);
+    }
+
+    /// 今からlimit後を期限にする (すでにそれより早い期限があればそちらを残す)
+    pub fn stop_after(&self, limit: Duration) {
+        let deadline = self.elapsed().saturating_add(limit.as_nanos().min(u64::MAX as u128) as u64);
+        self.shared.deadline.fetch_min(deadline.min(NO_DEADLINE - 1), Ordering::Relaxed);
+    }
+
+    // originからの経過時間 (ナノ秒)
+    fn elapsed(&self) -> u64 {
+        self.shared.origin.elapsed().as_nanos().min(u64::MAX as u128) as u64
+    }
+}
+
+/// 探索の調整用パラメータ (`--param 名前=値`で変更できる)
+#[derive(Clone, Debug)]
+pub struct SearchParams {
+    /// LMR (後半の手を浅く読む) を使う残り深さの下限
+    pub lmr_min_depth: usize,
+    /// この番目 (0始まり) 以降の手をLMRの対象にする
+    pub lmr_min_index: usize,
+    /// LMRで減らす深さ (0でLMRを使わない)
+    pub lmr_reduction: usize,
+    /// 隅を取る手を延長する深さ
+    pub corner_extension: usize,
+    /// 相手の応手が一つしかなくなる手を延長する深さ
+    pub single_reply_extension: usize,
+    /// 一本の読み筋で延長できる深さの合計の上限
+    pub max_extensions: usize,
+}
+
+impl Default for SearchParams {
+    fn default() -> Self {
+        SearchParams {
+            lmr_min_depth: 3,
+            lmr_min_index: 3,
+            lmr_reduction: 1,
+            corner_extension: 1,
+            single_reply_extension: 1,
+            max_extensions: 2,
+        }
+    }
+}
+
+impl SearchParams {
+    /// "名前=値" の形式で一つのパラメータを設定する
+    pub fn set(&mut self, assignment: &str) -> Result<(), String> {
+        let (name, value) = assignment
+            .split_once('=')
+            .ok_or(format!("expected name=value: {}", assignment))?;
+        let value: usize = value
+            .trim()
+            .parse()
+            .map_err(|_| format!("invalid value for {}: {}", name, value))?;
+        match name.trim() {
+            "lmr_min_depth" => self.lmr_min_depth = value,
+            "lmr_min_index" => self.lmr_min_index = value,
+            "lmr_reduction" => self.lmr_reduction = value,
+            "corner_extension" => self.corner_extension = value,
+            "single_reply_extension" => self.single_reply_extension = value,
+            "max_extensions" => self.max_extensions = value,
+            _ => return Err(format!("unknown parameter: {}", name)),
+        }
+        Ok(())
+    }
+}
+
+// 評価値キャッシュの大きさ (2^16エントリ = 1MiB)
+const EVAL_CACHE_BITS: u32 = 16;
+
+/// 探索全体で共有する状態
+/// 乱数はすべてここの`rng`から引くので、同じ種からは同じ手順が再現される
+pub struct SearchContext {
+    pub stop: StopFlag,
+    pub rng: StdRng,
+    pub params: SearchParams,
+    // 末端の評価値のキャッシュ (スレッド間で共有できる。評価関数を変えると作り直す)
+    eval_cache: Arc<EvalCache>,
+    evaluator: Arc<dyn Evaluator>,
+    // 評価関数がネットワークのときに使う、読み筋に沿ったアキュムレータ
+    accumulators: AccumulatorStack,
+    /// alpha_betaで訪れた局面の数
+    pub nodes: u64,
+    // 現在の読み筋で使った延長の合計
+    extensions: usize,
+    /// 直前の`search`で求めた手番側の評価値 (思考時間の配分に使う)
+    pub last_score: Option<Score>,
+    seed: u64,
+    games: u64,
+}
+
+impl SearchContext {
+    pub fn new(seed: u64) -> Self {
+        SearchContext {
+            stop: StopFlag::new(),
+            rng: StdRng::seed_from_u64(seed),
+            params: SearchParams::default(),
+            eval_cache: Arc::new(EvalCache::new(EVAL_CACHE_BITS)),
+            evaluator: Arc::new(Heuristic::new()),
+            accumulators: AccumulatorStack::new(None),
+            nodes: 0,
+            extensions: 0,
+            last_score: None,
+            seed,
+            games: 0,
+        }
+    }
+
+    /// 新しい対局を始める。対局ごとに種を seed, seed + 1, ... と振り直し、その種を返す
+    /// (返した種を`--seed`に渡せば、その対局を最初の対局として再現できる)
+    pub fn start_game(&mut self) -> u64 {
+        let seed = self.seed.wrapping_add(self.games);
+        self.games += 1;
+        self.rng = StdRng::seed_from_u64(seed);
+        self.last_score = None;
+        seed
+    }
+
+    pub fn evaluator(&self) -> &dyn Evaluator {
+        self.evaluator.as_ref()
+    }
+
+    /// 評価関数を差し替える。キャッシュした評価値は使えなくなるので捨てる
+    pub fn set_evaluator(&mut self, evaluator: Arc<dyn Evaluator>) {
+        self.accumulators = AccumulatorStack::new(evaluator.network());
+        self.evaluator = evaluator;
+        self.eval_cache = Arc::new(EvalCache::new(EVAL_CACHE_BITS));
+    }
+
+    /// 現在の(最後に始めた)対局の種
+    pub fn game_seed(&self) -> u64 {
+        self.seed.wrapping_add(self.games.saturating_sub(1))
+    }
+}
+
+// Fisher–Yatesによるシャッフル
+fn shuffle(mvs: &mut [u64], rng: &mut StdRng) {
+    let n = mvs.len();
+    for i in 0..n.saturating_sub(1) {
+        mvs.swap(i, i + rng.gen_range(0..n - i));
+    }
+}
+
+// 合法手をmvsに詰めて手の数を返す (探索の節点ごとにVecを確保しないよう配列を使う)
+fn collect_moves(legals: u64, mvs: &mut [u64]) -> usize {
+    let mut n = 0;
+    let mut bits = legals;
+    while bits != 0 {
+        mvs[n] = bits & bits.wrapping_neg();
+        bits &= bits - 1;
+        n += 1;
+    }
+    n
+}
+
+// 四隅のビットマスク
+const CORNERS: u64 = 0x8100000000000081;
+// 隅の隣 (X打ち・C打ち) のビットマスク
+const NEAR_CORNERS: u64 = 0x42c300000000c342;
+
+// 手の並べ替え用の優先度 (小さいほど先に読む)
+// 隅を先に、隅の隣を最後に読むことで、LMRで浅く読まれるのが悪い手になりやすくする
+fn move_priority(mv: u64) -> u8 {
+    if mv & CORNERS != 0 {
+        0
+    } else if mv & NEAR_CORNERS != 0 {
+        2
+    } else {
+        1
+    }
+}
+
+// 合法手を優先度の順にmvsに詰めて手の数を返す。同じ優先度の手の間はランダムに並べる
+fn order_moves(legals: u64, mvs: &mut [u64; 64], rng: &mut StdRng) -> usize {
+    let mut n = 0;
+    for class in [legals & CORNERS, legals & !CORNERS & !NEAR_CORNERS, legals & NEAR_CORNERS] {
+        let count = collect_moves(class, &mut mvs[n..]);
+        shuffle(&mut mvs[n..n + count], rng);
+        n += count;
+    }
+    n
+}
+
+// 手の延長量を決める。延長の合計はmax_extensionsまでに抑える
+fn extension(child: &Board, mv: u64, ctx: &SearchContext) -> usize {
+    let params = &ctx.params;
+    let budget = params.max_extensions.saturating_sub(ctx.extensions);
+    if budget == 0 {
+        return 0;
+    }
+    let ext = if mv & CORNERS != 0 {
+        params.corner_extension
+    } else if params.single_reply_extension > 0 && child.legals().0.count_ones() == 1 {
+        params.single_reply_extension
+    } else {
+        0
+    };
+    ext.min(budget)
+}
+
+// 中断フラグの期限を確かめる間隔 (局面数)
+const POLL_INTERVAL: u64 = 1024;
+
 // alpha-beta探索
-pub fn alpha_beta(board: &Board, alpha: i32, beta: i32, depth: usize, pass: bool) -> i32 {
+// 同じ優先度の手の間はランダムに並べ、後半の手はLMRで浅く読んで良さそうなら読み直す
+// 隅を取る手や相手の応手が一つになる手は延長する
+// 中断された場合の返り値は意味を持たないので、呼び出し側で捨てること
+pub fn alpha_beta(board: &mut Board, alpha: Score, beta: Score, depth: usize, pass: bool, ctx: &mut SearchContext) -> Score {
+    if ctx.nodes.is_multiple_of(POLL_INTERVAL) {
+        ctx.stop.poll();
+    }
+    if ctx.stop.is_stopped() {
+        return 0;
+    }
+    ctx.nodes += 1;
     let (black_mvs, hints) = board.legals();
     if black_mvs == 0 && pass {
         // double pass
         board.evaluate_end()
     } else if black_mvs == 0 {
-        let mut new_board = board.clone();
-        new_board.exchange();
-        -alpha_beta(&new_board, -beta, -alpha, depth, true)
+        let undo = board.make_pass();
+        let score = -alpha_beta(board, -beta, -alpha, depth, true, ctx);
+        board.unmake_move(undo);
+        score
     } else if depth == 0 {
-        let mut new_board = board.clone();
-        new_board.exchange();
-        let (white_mvs, _) = new_board.legals();
-        board.evaluate(black_mvs, white_mvs)
-    } else {
-        let mut mvs = (0..64)
-            .map(|i| 1 << i)
-            .filter(|&mv| mv & black_mvs == mv)
-            .collect::<Vec<_>>();
-        let n = mvs.len();
-        for i in 0..n - 1 {
-            mvs.swap(i, i + random::<usize>() % (n - i));
+        let key = board.hash();
+        if let Some(score) = ctx.eval_cache.probe(key) {
+            return score;
         }
+        let score = match ctx.accumulators.evaluate(board) {
+            Some(score) => score,
+            None => {
+                board.exchange();
+                let (white_mvs, _) = board.legals();
+                board.exchange();
+                ctx.evaluator.evaluate(board, black_mvs, white_mvs)
+            }
+        };
+        // 評価関数の値が読み切った勝ち・負けと紛れないようにする
+        let score = score::clamp(score);
+        ctx.eval_cache.store(key, score);
+        score
+    } else {
+        let mut mvs = [0u64; 64];
+        let n = order_moves(black_mvs, &mut mvs, &mut ctx.rng);
         let mut alpha = alpha;
-        for &mov in mvs.iter() {
-            let mut new_board = board.clone();
-            new_board.next(mov, hints);
-            new_board.exchange();
-            let score = -alpha_beta(&new_board, -beta, -alpha, depth - 1, false);
+        for (i, &mov) in mvs[..n].iter().enumerate() {
+            let undo = board.make_move(mov, hints);
+            ctx.accumulators.push(board, &undo);
+            let ext = extension(board, mov, ctx);
+            ctx.extensions += ext;
+            let params = &ctx.params;
+            let reduction = if ext == 0
+                && depth >= params.lmr_min_depth
+                && i >= params.lmr_min_index
+                && move_priority(mov) > 0
+            {
+                params.lmr_reduction.min(depth - 1)
+            } else {
+                0
+            };
+            // 浅いnull windowの探索でalphaを超えそうなときだけ本来の深さで読み直す
+            let fails_low = reduction > 0
+                && -alpha_beta(board, -alpha - 1, -alpha, depth - 1 - reduction, false, ctx) <= alpha;
+            let score = if fails_low {
+                alpha
+            } else {
+                -alpha_beta(board, -beta, -alpha, depth - 1 + ext, false, ctx)
+            };
+            board.unmake_move(undo);
+            ctx.accumulators.pop();
+            ctx.extensions -= ext;
+            if ctx.stop.is_stopped() {
+                break;
+            }
             if alpha < score {
                 alpha = score;
             }
@@ -135,49 +444,336 @@ pub fn alpha_beta(board: &Board, alpha: i32, beta: i32, depth: usize, pass: bool
 }
 
 
-pub fn search(board: &Board, depth: usize, time_level: usize) -> (u64, [(u64, u64); 4]) {
-    // 1. まず定石データベースを検索する
-    if let Some(book_move) = OPENING_BOOK.get(board) {
+/// 探索せずに即答できる局面なら、その手を返す (0はパス)
+/// パス・合法手が一つだけの局面・定石にある局面・どの手を打っても終局する局面が対象
+pub fn instant_move(board: &Board) -> Option<u64> {
+    let (mvs, hints) = board.legals();
+    if mvs == 0 {
+        return Some(0);
+    }
+    if mvs.count_ones() == 1 {
+        eprintln!("[Info] Forced move");
+        return Some(mvs);
+    }
+    if let Some(book_move) = opening_book().get(board) {
         eprintln!("[Info] Move from Opening Book!");
-        // 定石手が見つかった場合、既存の返り値の型に合わせて返す（配列の部分はダミーデータで埋める）
+        return Some(book_move);
+    }
+    // どの手を打っても終局するなら、石差が最大の手を選ぶだけでよい
+    let mut best: Option<(i32, u64)> = None;
+    for i in 0..64 {
+        let mv = 1u64 << i;
+        if mvs & mv == 0 {
+            continue;
+        }
+        let mut new_board = board.clone();
+        new_board.next(mv, hints);
+        let (black_mvs, _) = new_board.legals();
+        new_board.exchange();
+        let (white_mvs, _) = new_board.legals();
+        if black_mvs != 0 || white_mvs != 0 {
+            return None;
+        }
+        let diff = new_board.opponent().count_ones() as i32 - new_board.player().count_ones() as i32;
+        if best.is_none_or(|(d, _)| d < diff) {
+            best = Some((diff, mv));
+        }
+    }
+    eprintln!("[Info] Every move ends the game");
+    best.map(|(_, mv)| mv)
+}
+
+// 読み切りの前に手を並べるための浅い探索の深さ (読み切りが中断されたときはこの結果を使う)
+const ENDGAME_ORDER_DEPTH: usize = 4;
+
+// 根の手をmvsの順に一つの深さで読み、最後まで読み終えた手の中で最善の (手, 評価値) を返す
+// 最初の手を読み終える前に中断されたらNone
+fn search_root(root: &mut Board, mvs: &[u64], hints: [(u64, u64); 4], depth: usize, window: (Score, Score), ctx: &mut SearchContext) -> Option<(u64, Score)> {
+    let (mut alpha, beta) = window;
+    let mut best = None;
+    for &mov in mvs.iter() {
+        let undo = root.make_move(mov, hints);
+        let score = -alpha_beta(root, -beta, -alpha, depth, false, ctx);
+        root.unmake_move(undo);
+        if ctx.stop.is_stopped() {
+            break;
+        }
+        // どの手もalphaを超えなければ最初の手 (前の深さの最善手) を残す
+        if best.is_none() || alpha < score {
+            alpha = alpha.max(score);
+            best = Some((mov, alpha));
+        }
+        if alpha >= beta {
+            break;
+        }
+    }
+    best
+}
+
+// mvの手をmvsの先頭に移す (他の手の順序は変えない)
+fn move_to_front(mvs: &mut [u64], mv: Option<(u64, Score)>) {
+    if let Some(i) = mv.and_then(|(mv, _)| mvs.iter().position(|&m| m == mv)) {
+        mvs[..=i].rotate_right(1);
+    }
+}
+
+// 反復深化で探索する。深さごとに前の深さの最善手を先に読み、
+// 中断された場合は、前の深さの最善手か、今の深さで読み終えた手の中での最善手を返す
+pub fn search(board: &Board, depth: usize, time_level: usize, ctx: &mut SearchContext) -> (u64, [(u64, u64); 4]) {
+    // 1. パス・一手しかない局面・定石などは探索せずに即答する
+    if let Some(mv) = instant_move(board) {
         let (_, hints) = board.legals();
-        return (book_move, hints);
+        return (mv, hints);
     }
 
-    // 2. 定石が見つからなかった場合、普通の探索処理を実行する
+    // 2. 読み切りモードでは、以前に読み切った局面ならその結果を使う
     let (mvs, hints) = board.legals();
-    if mvs == 0 {
-        (0, hints)
+    // 46手以降は読み切りモード
+    let endgame = board.turns >= 46;
+    if endgame {
+        if let Some(solved) = SOLVED.get(board) {
+            eprintln!("[Info] Move from solved cache");
+            return (solved.best_move, hints);
+        }
+    }
+
+    // 3. 普通の探索処理を実行する
+    let legals = mvs;
+    let mut mvs = [0u64; 64];
+    let n = collect_moves(legals, &mut mvs);
+    let mvs = &mut mvs[..n];
+    shuffle(mvs, &mut ctx.rng);
+    let full = (-score::INFINITY, score::INFINITY);
+    // 読み切りでは勝敗だけを調べる窓にする (勝ちはWINを超え、負けは-WINを下回る)
+    let iterations: Vec<(usize, (Score, Score))> = if endgame {
+        vec![(ENDGAME_ORDER_DEPTH, full), (64, (-score::WIN, score::WIN))]
     } else {
-        let mut mvs = (0..64)
-            .map(|i| 1 << i)
-            .filter(|&mv| mv & mvs == mv)
-            .collect::<Vec<_>>();
-        let n = mvs.len();
-        for i in 0..n - 1 {
-            mvs.swap(i, i + random::<usize>() % (n - i));
-        }
-        let mut sel_mov = mvs[0];
-        let mut alpha = -Board::MAX_EVAL;
-        let beta = Board::MAX_EVAL;
-        for &mov in mvs.iter() {
-            let mut new_board = board.clone();
-            new_board.next(mov, hints);
-            new_board.exchange();
-            // 46手以降は読み切りモード
-            let score = if new_board.turns > 46 {
-                -alpha_beta(&new_board, -beta, -alpha, 64, false)
-            } else {
-                -alpha_beta(&new_board, -beta, -alpha, depth - time_level * 2, false)
-            };
-            if alpha < score {
-                alpha = score;
-                sel_mov = mov;
+        (1..=depth.saturating_sub(time_level * 2).max(1)).map(|d| (d, full)).collect()
+    };
+    let mut root = board.clone();
+    let mut best: Option<(u64, Score)> = None;
+    let mut solved = None;
+    for (d, window) in iterations {
+        move_to_front(mvs, best);
+        let result = search_root(&mut root, mvs, hints, d, window, ctx);
+        if ctx.stop.is_stopped() {
+            eprintln!("[Info] Search stopped at depth {}", d);
+            // 前の深さの最善手を最初に読んでいるので、読み終えた手があればその中の最善手のほうが確か
+            if result.is_some() {
+                best = result;
+            }
+            break;
+        }
+        best = result;
+        if d == 64 {
+            solved = Some(SolveKind::Wld);
+        }
+    }
+    // 負けと分かった局面では、勝敗だけの窓ではどの手も-WINになって区別がつかないので、
+    // 負ける石差を正確に読んで一番小さく負ける手を選ぶ
+    if solved.is_some() && best.is_some_and(|(_, score)| score <= -score::WIN) {
+        move_to_front(mvs, best);
+        let result = search_root(&mut root, mvs, hints, 64, (-score::INFINITY, -score::WIN), ctx);
+        if ctx.stop.is_stopped() {
+            eprintln!("[Info] Search stopped while solving the loss exactly");
+            solved = None;
+        } else {
+            solved = Some(SolveKind::Exact);
+        }
+        if result.is_some() {
+            best = result;
+        }
+    }
+    let Some((sel_mov, score)) = best else {
+        // 最初の深さの最初の手も読み終えられなかった
+        return (mvs[0], hints);
+    };
+    if score::is_proven(score) {
+        eprintln!("[Info] Score {}", score::format(score));
+    } else {
+        eprintln!("[Info] Score {} (win {:.0}%)", score::format(score), score::win_probability(score) * 100.0);
+    }
+    ctx.last_score = Some(score);
+    match solved {
+        // 窓の外に出た勝ちは石差が正確でないので、勝敗だけを記録する
+        Some(SolveKind::Wld) => SOLVED.insert(board, Solved { kind: SolveKind::Wld, score: score.signum(), best_move: sel_mov }),
+        Some(SolveKind::Exact) => SOLVED.insert(board, Solved { kind: SolveKind::Exact, score: score + score::WIN, best_move: sel_mov }),
+        None => {}
+    }
+    (sel_mov, hints)
+}
+
+/// multi-PVで求めた評価値の種類
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Bound {
+    /// 正確な値
+    Exact,
+    /// 最大でもこの値
+    Upper,
+}
+
+/// multi-PVの一手分の結果
+#[derive(Clone, Debug)]
+pub struct RootMove {
+    pub mv: u64,
+    pub score: Score,
+    pub bound: Bound,
+    /// mvから始まる読み筋 (0はパス)
+    pub pv: Vec<u64>,
+}
+
+// 読み筋を記録するalpha-beta探索 (multi-PVの上位の手にだけ使う)
+fn pv_search(board: &mut Board, alpha: Score, beta: Score, depth: usize, pass: bool, ctx: &mut SearchContext, pv: &mut Vec<u64>) -> Score {
+    pv.clear();
+    if ctx.stop.is_stopped() {
+        return 0;
+    }
+    let (black_mvs, hints) = board.legals();
+    if black_mvs == 0 && pass {
+        board.evaluate_end()
+    } else if black_mvs == 0 {
+        let undo = board.make_pass();
+        let mut child_pv = Vec::new();
+        let score = -pv_search(board, -beta, -alpha, depth, true, ctx, &mut child_pv);
+        board.unmake_move(undo);
+        pv.push(0);
+        pv.append(&mut child_pv);
+        score
+    } else if depth == 0 {
+        alpha_beta(board, alpha, beta, 0, pass, ctx)
+    } else {
+        let mut mvs = [0u64; 64];
+        let n = collect_moves(black_mvs, &mut mvs);
+        shuffle(&mut mvs[..n], &mut ctx.rng);
+        let mut alpha = alpha;
+        let mut child_pv = Vec::new();
+        for &mov in mvs[..n].iter() {
+            let undo = board.make_move(mov, hints);
+            let score = -pv_search(board, -beta, -alpha, depth - 1, false, ctx, &mut child_pv);
+            board.unmake_move(undo);
+            if ctx.stop.is_stopped() {
+                break;
+            }
+            if alpha < score || pv.is_empty() {
+                if alpha < score {
+                    alpha = score;
+                }
+                pv.clear();
+                pv.push(mov);
+                pv.append(&mut child_pv);
             }
             if alpha >= beta {
                 break;
             }
         }
-        (sel_mov, hints)
+        alpha
+    }
+}
+
+/// 根の合法手をそれぞれ採点する (multi-PV)
+/// 上位n手 (n=0なら全部) は正確な評価値と読み筋を求め、それ以外の手は
+/// 「n番目の手の評価値以下」という上界だけを求める。結果は正確な値のn手、上界の手の順に、それぞれ評価値の高い順に並ぶ
+/// 上界の判定と正確な値は同じ探索 (pv_search) で求めるので、上界がn番目の正確な値を超えることはない
+/// 中断された場合は、探索し終えた手だけを返す
+pub fn search_multipv(board: &Board, depth: usize, n: usize, ctx: &mut SearchContext) -> Vec<RootMove> {
+    let (legals, hints) = board.legals();
+    let n = if n == 0 { 64 } else { n };
+    let depth = depth.max(1);
+    let mut mvs = [0u64; 64];
+    let count = collect_moves(legals, &mut mvs);
+    let mvs = &mut mvs[..count];
+    shuffle(mvs, &mut ctx.rng);
+
+    // 浅い探索で並べ替えておくと、上位の手を先に正確に求められる
+    let mut root = board.clone();
+    let mut order: Vec<(i32, usize)> = Vec::new();
+    for (i, &mv) in mvs.iter().enumerate() {
+        let undo = root.make_move(mv, hints);
+        order.push((-alpha_beta(&mut root, -score::INFINITY, score::INFINITY, depth.min(2), false, ctx), i));
+        root.unmake_move(undo);
+    }
+    order.sort_by_key(|&(score, _)| Reverse(score));
+
+    let mut exact: Vec<RootMove> = Vec::new();
+    let mut upper: Vec<RootMove> = Vec::new();
+    let mut pv = Vec::new();
+    for &(_, i) in order.iter() {
+        // 正確な値がn手そろっていれば、n番目の評価値を超えるかどうかだけを null window で調べる
+        // 超えた手は正確な値を求め直し、n番目から押し出された手は上界に回す
+        if exact.len() >= n {
+            let kth = exact[n - 1].score;
+            let undo = root.make_move(mvs[i], hints);
+            let score = -pv_search(&mut root, -(kth + 1), -kth, depth - 1, false, ctx, &mut pv);
+            root.unmake_move(undo);
+            if ctx.stop.is_stopped() {
+                break;
+            }
+            if score <= kth {
+                upper.push(RootMove { mv: mvs[i], score: kth, bound: Bound::Upper, pv: vec![mvs[i]] });
+                continue;
+            }
+        }
+        let undo = root.make_move(mvs[i], hints);
+        let score = -pv_search(&mut root, -score::INFINITY, score::INFINITY, depth - 1, false, ctx, &mut pv);
+        root.unmake_move(undo);
+        if ctx.stop.is_stopped() {
+            break;
+        }
+        let mut line = vec![mvs[i]];
+        line.append(&mut pv);
+        exact.push(RootMove { mv: mvs[i], score, bound: Bound::Exact, pv: line });
+        exact.sort_by_key(|r| Reverse(r.score));
+        if exact.len() > n {
+            // 押し出された手の正確な値はn番目の値以下なので、そのまま上界として使える
+            let mut pushed = exact.pop().unwrap();
+            pushed.bound = Bound::Upper;
+            pushed.pv.truncate(1);
+            upper.push(pushed);
+        }
+    }
+    // 上界を求めた時点のn番目の値は最後のn番目の値以下なので、正確な値の後に並べれば順序が崩れない
+    upper.sort_by_key(|r| Reverse(r.score));
+    exact.append(&mut upper);
+    exact
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::Game;
+    use std::thread;
+
+    #[test]
+    fn clones_share_the_deadline_and_reset() {
+        let flag = StopFlag::new();
+        let handle = flag.clone();
+        flag.stop_after(Duration::ZERO);
+        assert!(handle.poll());
+        flag.reset();
+        assert!(!handle.poll());
+        // 後から設定した遅い期限は早い期限を上書きしない
+        handle.stop_after(Duration::from_secs(3600));
+        flag.stop_after(Duration::ZERO);
+        handle.stop_after(Duration::from_secs(3600));
+        assert!(flag.poll());
+    }
+
+    #[test]
+    fn search_is_stopped_from_another_thread() {
+        // 定石にない中盤の局面
+        let board = Game::from_transcript("f5d6c3g5c6c5c4b6f6f4e6d7c7g6d8b5e7b3a6e3a5d3f3f7b4e8g4c8h5a3").unwrap().board().clone();
+        assert!(opening_book().get(&board).is_none());
+        let mut ctx = SearchContext::new(1);
+        let handle = ctx.stop.clone();
+        let stopper = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(200));
+            handle.stop();
+        });
+        let started = Instant::now();
+        let (mv, _) = search(&board, 30, 0, &mut ctx);
+        stopper.join().unwrap();
+        assert!(ctx.stop.is_stopped());
+        assert!(started.elapsed() < Duration::from_secs(10));
+        assert!(board.legals().0 & mv != 0);
     }
 }
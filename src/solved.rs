@@ -0,0 +1,176 @@
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::bitboard::Board;
+use crate::cmds::{idx_to_move, move_to_idx, move_to_string, string_to_move};
+
+/// 読み切った結果の種類
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SolveKind {
+    /// 正確な石差
+    Exact,
+    /// 勝ち(1)・引き分け(0)・負け(-1)だけ
+    Wld,
+}
+
+/// 読み切った局面の結果 (scoreは手番側から見たもの)
+#[derive(Clone, Copy, Debug)]
+pub struct Solved {
+    pub kind: SolveKind,
+    pub score: i32,
+    pub best_move: u64,
+}
+
+// ファイルを開かないときにメモリに持つ局面数の上限
+const DEFAULT_LIMIT: usize = 100000;
+
+struct Inner {
+    map: HashMap<(u64, u64), Solved>,
+    path: Option<PathBuf>,
+    limit: usize,
+}
+
+/// 読み切った局面のキャッシュ
+/// ファイルに一行一局面で追記していき、次回以降の起動時に読み込む
+/// 形式は `手番側の石(16進) 相手の石(16進) exact|wld 評価値 最善手`
+pub struct SolvedCache {
+    inner: Mutex<Inner>,
+}
+
+impl SolvedCache {
+    fn new() -> Self {
+        SolvedCache {
+            inner: Mutex::new(Inner { map: HashMap::new(), path: None, limit: DEFAULT_LIMIT }),
+        }
+    }
+
+    /// ファイルを読み込み、以降の結果をそこへ追記するようにする
+    /// メモリに持つ局面数はlimitまで (ファイルの整理は`compact`で行う)
+    pub fn open(&self, path: &Path, limit: usize) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.map = read_entries(path, limit);
+        inner.path = Some(path.to_path_buf());
+        inner.limit = limit;
+    }
+
+    pub fn get(&self, board: &Board) -> Option<Solved> {
+        let inner = self.inner.lock().unwrap();
+        let solved = inner.map.get(&(board.player(), board.opponent())).cloned()?;
+        // 壊れた行から読んだ手は使わない
+        let (legals, _) = board.legals();
+        let legal = if solved.best_move == 0 {
+            legals == 0
+        } else {
+            legals & solved.best_move == solved.best_move
+        };
+        if legal {
+            Some(solved)
+        } else {
+            None
+        }
+    }
+
+    /// 結果を登録し、新しい局面ならファイルに追記する
+    pub fn insert(&self, board: &Board, solved: Solved) {
+        let mut inner = self.inner.lock().unwrap();
+        let key = (board.player(), board.opponent());
+        if inner.map.contains_key(&key) || inner.map.len() >= inner.limit {
+            return;
+        }
+        inner.map.insert(key, solved);
+        if let Some(path) = &inner.path {
+            let result = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .and_then(|mut f| f.write_all(format_entry(key, &solved).as_bytes()));
+            if let Err(e) = result {
+                eprintln!("[Warn] Failed to append to {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+// プログラム全体で共有される読み切り結果のキャッシュ
+pub static SOLVED: Lazy<SolvedCache> = Lazy::new(SolvedCache::new);
+
+fn format_entry(key: (u64, u64), solved: &Solved) -> String {
+    format!(
+        "{:016x} {:016x} {} {} {}\n",
+        key.0,
+        key.1,
+        match solved.kind {
+            SolveKind::Exact => "exact",
+            SolveKind::Wld => "wld",
+        },
+        solved.score,
+        move_to_string(idx_to_move(&solved.best_move))
+    )
+}
+
+fn parse_entry(line: &str) -> Option<((u64, u64), Solved)> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    if fields.len() != 5 {
+        return None;
+    }
+    let key = (
+        u64::from_str_radix(fields[0], 16).ok()?,
+        u64::from_str_radix(fields[1], 16).ok()?,
+    );
+    if key.0 & key.1 != 0 {
+        return None;
+    }
+    let kind = match fields[2] {
+        "exact" => SolveKind::Exact,
+        "wld" => SolveKind::Wld,
+        _ => return None,
+    };
+    let score = fields[3].parse().ok()?;
+    let best_move = move_to_idx(&string_to_move(fields[4])?);
+    Some((key, Solved { kind, score, best_move }))
+}
+
+// ファイルを読む。同じ局面が複数あれば後の行を優先し、正確な石差はWLDより優先する
+fn read_entries(path: &Path, limit: usize) -> HashMap<(u64, u64), Solved> {
+    let mut map = HashMap::new();
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(_) => return map,
+    };
+    for (i, line) in text.lines().enumerate() {
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match parse_entry(line) {
+            Some((key, solved)) => {
+                let keep_old = matches!(map.get(&key), Some(Solved { kind: SolveKind::Exact, .. }))
+                    && solved.kind == SolveKind::Wld;
+                if !keep_old && (map.len() < limit || map.contains_key(&key)) {
+                    map.insert(key, solved);
+                }
+            }
+            None => eprintln!("[Warn] {}:{}: invalid solved entry", path.display(), i + 1),
+        }
+    }
+    map
+}
+
+/// キャッシュのファイルを整理する
+/// 重複と壊れた行を取り除き、空きマスの多い (読み切りに時間のかかる) 局面から順にlimit個だけ残す
+/// (整理前の行数, 整理後の局面数) を返す
+pub fn compact(path: &Path, limit: usize) -> std::io::Result<(usize, usize)> {
+    let before = fs::read_to_string(path)?.lines().count();
+    let mut entries: Vec<((u64, u64), Solved)> = read_entries(path, usize::MAX).into_iter().collect();
+    entries.sort_by_key(|(key, _)| ((key.0 | key.1).count_ones(), *key));
+    entries.truncate(limit);
+    let mut writer = BufWriter::new(File::create(path)?);
+    for (key, solved) in entries.iter() {
+        writer.write_all(format_entry(*key, solved).as_bytes())?;
+    }
+    writer.flush()?;
+    Ok((before, entries.len()))
+}
@@ -0,0 +1,184 @@
+use crate::bitboard::Board;
+
+// ビットボードの対称変換
+// ビット63がA1、ビット0がH8で、8ビットずつが盤面の一行 (上位のバイトほど上の行) になっている
+
+/// 左右反転 (A列とH列を入れ替える)
+pub fn flip_horizontal(x: u64) -> u64 {
+    const K1: u64 = 0x5555555555555555;
+    const K2: u64 = 0x3333333333333333;
+    const K4: u64 = 0x0f0f0f0f0f0f0f0f;
+    let x = ((x >> 1) & K1) | ((x & K1) << 1);
+    let x = ((x >> 2) & K2) | ((x & K2) << 2);
+    ((x >> 4) & K4) | ((x & K4) << 4)
+}
+
+/// 上下反転 (1行目と8行目を入れ替える)
+pub fn flip_vertical(x: u64) -> u64 {
+    x.swap_bytes()
+}
+
+/// A1-H8の対角線で反転する
+pub fn flip_diagonal(x: u64) -> u64 {
+    const K1: u64 = 0x5500550055005500;
+    const K2: u64 = 0x3333000033330000;
+    const K4: u64 = 0x0f0f0f0f00000000;
+    let mut x = x;
+    let t = K4 & (x ^ (x << 28));
+    x ^= t ^ (t >> 28);
+    let t = K2 & (x ^ (x << 14));
+    x ^= t ^ (t >> 14);
+    let t = K1 & (x ^ (x << 7));
+    x ^ t ^ (t >> 7)
+}
+
+/// A8-H1の対角線で反転する
+pub fn flip_anti_diagonal(x: u64) -> u64 {
+    const K1: u64 = 0xaa00aa00aa00aa00;
+    const K2: u64 = 0xcccc0000cccc0000;
+    const K4: u64 = 0xf0f0f0f00f0f0f0f;
+    let mut x = x;
+    let t = x ^ (x << 36);
+    x ^= K4 & (t ^ (x >> 36));
+    let t = K2 & (x ^ (x << 18));
+    x ^= t ^ (t >> 18);
+    let t = K1 & (x ^ (x << 9));
+    x ^ t ^ (t >> 9)
+}
+
+/// 盤面の8通りの対称変換
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Symmetry {
+    Identity,
+    FlipHorizontal,
+    FlipVertical,
+    /// 180度回転
+    Rotate180,
+    /// A1-H8の対角線で反転
+    FlipDiagonal,
+    /// A8-H1の対角線で反転
+    FlipAntiDiagonal,
+    /// 反時計回りに90度回転 (A1がA8へ移る)
+    Rotate90,
+    /// 時計回りに90度回転 (A1がH1へ移る)
+    Rotate270,
+}
+
+impl Symmetry {
+    pub const ALL: [Symmetry; 8] = [
+        Symmetry::Identity,
+        Symmetry::FlipHorizontal,
+        Symmetry::FlipVertical,
+        Symmetry::Rotate180,
+        Symmetry::FlipDiagonal,
+        Symmetry::FlipAntiDiagonal,
+        Symmetry::Rotate90,
+        Symmetry::Rotate270,
+    ];
+
+    /// ビットボード (石の集合や手) を変換する
+    pub fn apply(self, x: u64) -> u64 {
+        match self {
+            Symmetry::Identity => x,
+            Symmetry::FlipHorizontal => flip_horizontal(x),
+            Symmetry::FlipVertical => flip_vertical(x),
+            Symmetry::Rotate180 => x.reverse_bits(),
+            Symmetry::FlipDiagonal => flip_diagonal(x),
+            Symmetry::FlipAntiDiagonal => flip_anti_diagonal(x),
+            Symmetry::Rotate90 => flip_vertical(flip_diagonal(x)),
+            Symmetry::Rotate270 => flip_diagonal(flip_vertical(x)),
+        }
+    }
+
+    /// 逆変換 (変換後の手を元の盤面の手に戻すのに使う)
+    pub fn inverse(self) -> Symmetry {
+        match self {
+            Symmetry::Rotate90 => Symmetry::Rotate270,
+            Symmetry::Rotate270 => Symmetry::Rotate90,
+            other => other,
+        }
+    }
+}
+
+impl Board {
+    /// 盤面を対称変換する (手番はそのまま)
+    pub fn transform(&self, sym: Symmetry) -> Board {
+        Board::from_discs(sym.apply(self.black()), sym.apply(self.white()), self.side())
+    }
+
+    /// 対称な8つの盤面のうち (手番側の石, 相手の石) が最小のものと、そこへの変換を返す
+    /// 元の盤面の手mvは`sym.apply(mv)`で標準形の手に、標準形の手は`sym.inverse().apply(mv)`で元に戻る
+    pub fn canonical(&self) -> (Board, Symmetry) {
+        let (player, opponent) = (self.player(), self.opponent());
+        let sym = Symmetry::ALL
+            .iter()
+            .copied()
+            .min_by_key(|sym| (sym.apply(player), sym.apply(opponent)))
+            .unwrap();
+        (self.transform(sym), sym)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cmds::{move_to_idx, string_to_move};
+    use crate::game::Game;
+
+    fn sq(name: &str) -> u64 {
+        move_to_idx(&string_to_move(name).unwrap())
+    }
+
+    #[test]
+    fn transforms_move_single_squares() {
+        let expected = [
+            (Symmetry::Identity, "c2", "a1"),
+            (Symmetry::FlipHorizontal, "f2", "h1"),
+            (Symmetry::FlipVertical, "c7", "a8"),
+            (Symmetry::Rotate180, "f7", "h8"),
+            (Symmetry::FlipDiagonal, "b3", "a1"),
+            (Symmetry::FlipAntiDiagonal, "g6", "h8"),
+            (Symmetry::Rotate90, "b6", "a8"),
+            (Symmetry::Rotate270, "g3", "h1"),
+        ];
+        for (sym, c2, a1) in expected {
+            assert_eq!(sym.apply(sq("c2")), sq(c2), "{:?}", sym);
+            assert_eq!(sym.apply(sq("a1")), sq(a1), "{:?}", sym);
+        }
+    }
+
+    #[test]
+    fn inverse_undoes_every_transform() {
+        for sym in Symmetry::ALL {
+            for bit in 0..64 {
+                assert_eq!(sym.inverse().apply(sym.apply(1 << bit)), 1 << bit, "{:?}", sym);
+            }
+            let x = 0x0123_4567_89ab_cdef;
+            assert_eq!(sym.inverse().apply(sym.apply(x)), x);
+            assert_eq!(sym.apply(sym.inverse().apply(x)), x);
+        }
+    }
+
+    #[test]
+    fn canonical_is_the_same_for_symmetric_positions() {
+        let board = Game::from_transcript("f5d6c3d3c4f4").unwrap().board().clone();
+        let (canonical, _) = board.canonical();
+        for sym in Symmetry::ALL {
+            let transformed = board.transform(sym);
+            assert_eq!(transformed.canonical().0, canonical, "{:?}", sym);
+            // 変換した手は変換した盤面で打てる
+            let (legals, _) = board.legals();
+            assert_eq!(sym.apply(legals), transformed.legals().0, "{:?}", sym);
+        }
+        // 元の盤面の手はcanonicalの変換で標準形の手に、逆変換で元の手に戻る
+        let (canonical, sym) = board.canonical();
+        let (legals, _) = board.legals();
+        let mut bits = legals;
+        while bits != 0 {
+            let mv = bits & bits.wrapping_neg();
+            assert!(canonical.legals().0 & sym.apply(mv) != 0);
+            assert_eq!(sym.inverse().apply(sym.apply(mv)), mv);
+            bits &= bits - 1;
+        }
+    }
+}
@@ -0,0 +1,255 @@
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::arena;
+use crate::cmds::Color;
+use crate::dataset::Sample;
+use crate::engine::Engine;
+use crate::evaluator::{make_evaluator, make_tunable, Evaluator, EvaluatorKind};
+use crate::game::Game;
+use crate::score;
+use crate::tuner::{Position, Tunable};
+
+/// TD(λ)による自己対局学習の設定
+pub struct TdOptions {
+    /// 学習する評価関数
+    pub kind: EvaluatorKind,
+    /// チェックポイントとログを置くディレクトリ
+    pub dir: PathBuf,
+    /// 自己対局の局数 (再開したときはそれまでの局数も含む)
+    pub games: usize,
+    /// 時間の上限 (これを過ぎたら今の対局を終えてから止める)
+    pub time_limit: Option<Duration>,
+    pub lr: f64,
+    pub lambda: f64,
+    /// 各局の最初にランダムに打つ手数 (この間の局面は学習に使わない)
+    pub random_plies: usize,
+    /// チェックポイントを書き出す間隔 (局数)
+    pub checkpoint_every: usize,
+    /// チェックポイントごとに最初と一つ前のチェックポイントと対戦する局数 (0なら対戦しない)
+    /// 対局はrandom_plies手をランダムに打った序盤から、先後を入れ替えて二局ずつ打つ
+    pub match_games: usize,
+    pub seed: u64,
+}
+
+// 勾配を差分で求めるときのパラメータの変化量 (段階の補間で丸められないよう大きめにとる)
+// 評価値はパラメータについて線形なので、差分を変化量で割れば勾配になる
+const DELTA: i32 = 64;
+
+fn checkpoint_path(dir: &Path, games: usize) -> PathBuf {
+    dir.join(format!("ckpt-{:06}.txt", games))
+}
+
+// dirにある最新のチェックポイント (局数, パス)
+fn latest_checkpoint(dir: &Path) -> Result<Option<(usize, PathBuf)>, String> {
+    let entries = fs::read_dir(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+    let mut latest: Option<(usize, PathBuf)> = None;
+    for entry in entries.flatten() {
+        let name = entry.file_name();
+        let games = name
+            .to_str()
+            .and_then(|n| n.strip_prefix("ckpt-"))
+            .and_then(|n| n.strip_suffix(".txt"))
+            .and_then(|n| n.parse::<usize>().ok());
+        if let Some(games) = games {
+            if latest.as_ref().is_none_or(|(g, _)| *g < games) {
+                latest = Some((games, entry.path()));
+            }
+        }
+    }
+    Ok(latest)
+}
+
+// 書き出している途中で止められても壊れたチェックポイントが残らないよう、別名で書いてから置き換える
+fn save_checkpoint(tunable: &dyn Tunable, path: &Path) -> Result<(), String> {
+    let tmp = path.with_extension("tmp");
+    tunable.save(&tmp)?;
+    fs::rename(&tmp, path).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+// 表示し、ディレクトリのlog.txtにも追記する
+fn log(dir: &Path, line: &str) {
+    println!("{}", line);
+    let path = dir.join("log.txt");
+    let written = OpenOptions::new().create(true).append(true).open(&path).and_then(|mut f| writeln!(f, "{}", line));
+    if let Err(e) = written {
+        eprintln!("[Warn] {}: {}", path.display(), e);
+    }
+}
+
+// 今のパラメータの評価関数 (対局中は学習で変わらない)
+fn snapshot(kind: EvaluatorKind, params: &[i32]) -> Result<Arc<dyn Evaluator>, String> {
+    let mut tunable = make_tunable(kind, None)?;
+    tunable.set_params(params);
+    let tunable: Arc<dyn Tunable> = Arc::from(tunable);
+    Ok(tunable)
+}
+
+// 初期盤面からplies手をランダムに打った対局 (途中で終局したらそこまで)
+fn random_opening(plies: usize, rng: &mut StdRng) -> Game {
+    let mut game = Game::new();
+    while game.moves().len() < plies && !game.is_over() {
+        let (legals, _) = game.board().legals();
+        let mv = if legals == 0 {
+            0
+        } else {
+            // 下位から数えてk番目の合法手
+            let k = rng.gen_range(0..legals.count_ones());
+            let rest = (0..k).fold(legals, |bits, _| bits & (bits - 1));
+            rest & rest.wrapping_neg()
+        };
+        game.play(mv).expect("random opening made an illegal move");
+    }
+    game
+}
+
+// openingの続きをengineが両方の手番で打って一局を終え、その対局と打った局面を返す
+fn self_play(engine: &mut Engine, mut game: Game) -> (Game, Vec<Position>) {
+    let mut positions = Vec::new();
+    while !game.is_over() {
+        // 結果は終局後に分かるので、ここでは0にしておく
+        positions.push(Position::new(&Sample { board: game.board().clone(), target: 0.0 }));
+        let (mv, _) = engine.select_move(game.board(), 0);
+        game.play(mv).expect("self-play made an illegal move");
+    }
+    (game, positions)
+}
+
+// 一局分のTD(λ)の更新量と、TD誤差の絶対値の平均
+// 局面の値は評価値を勝率に直した黒の勝率で、最後の局面の次の値は実際の結果 (勝ち1, 引き分け0.5, 負け0)
+fn td_update(tunable: &mut dyn Tunable, params: &mut [i32], positions: &[Position], result: f64, lambda: f64) -> (Vec<f64>, f64) {
+    let black = |p: &Position| p.board.side() == Color::Black;
+    let evals: Vec<score::Score> = positions.iter().map(|p| p.evaluate(tunable)).collect();
+    let values: Vec<f64> = positions
+        .iter()
+        .zip(evals.iter())
+        .map(|(p, &e)| {
+            let w = score::win_probability(e);
+            if black(p) { w } else { 1.0 - w }
+        })
+        .collect();
+
+    // grads[t][i]: 局面tの黒の勝率のi番目のパラメータについての勾配
+    // 評価値の勾配を差分で求め、勝率への変換の傾き K w (1 - w) を掛ける (読み切った値は動かないので0)
+    let mut grads = vec![vec![0.0; params.len()]; positions.len()];
+    for i in 0..params.len() {
+        params[i] += DELTA;
+        tunable.set_params(params);
+        for (t, p) in positions.iter().enumerate() {
+            if score::is_proven(evals[t]) {
+                continue;
+            }
+            let w = score::win_probability(evals[t]);
+            let slope = score::K * w * (1.0 - w);
+            let d = (p.evaluate(tunable) - evals[t]) as f64 / DELTA as f64 * slope;
+            grads[t][i] = if black(p) { d } else { -d };
+        }
+        params[i] -= DELTA;
+    }
+    tunable.set_params(params);
+
+    let mut update = vec![0.0; params.len()];
+    let mut trace = vec![0.0; params.len()];
+    let mut total_error = 0.0;
+    for t in 0..positions.len() {
+        let next = if t + 1 < positions.len() { values[t + 1] } else { result };
+        let delta = next - values[t];
+        total_error += delta.abs();
+        for i in 0..params.len() {
+            trace[i] = lambda * trace[i] + grads[t][i];
+            update[i] += delta * trace[i];
+        }
+    }
+    (update, total_error / positions.len().max(1) as f64)
+}
+
+/// TD(λ)で評価関数の重みを自己対局から学ぶ
+/// learnerが今の重みで両方の手番を打ち、一局ごとに各局面の評価値 (を勝率に直したもの) が次の局面の値と終局の結果に近づくよう重みを動かす
+/// 重みはcheckpoint_every局ごとにdirへ書き出し、最初と一つ前のチェックポイントとの対戦結果をlog.txtに残す
+/// dirにチェックポイントがあれば最新のものから再開する (なければstartの重みから始める)
+pub fn train(options: &TdOptions, start: Option<&Path>, learner: &mut Engine, opponent: &mut Engine) -> Result<(), String> {
+    let dir = &options.dir;
+    fs::create_dir_all(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+    let (mut tunable, mut played) = match latest_checkpoint(dir)? {
+        Some((games, path)) => {
+            println!("Resuming from {} ({} games)", path.display(), games);
+            (make_tunable(options.kind, Some(&path))?, games)
+        }
+        None => {
+            let tunable = make_tunable(options.kind, start)?;
+            save_checkpoint(tunable.as_ref(), &checkpoint_path(dir, 0))?;
+            (tunable, 0)
+        }
+    };
+    let mut params = tunable.params();
+    // 一局ごとの更新は1より小さいことが多いので、実数で積み上げて丸めたものを使う
+    let mut weights: Vec<f64> = params.iter().map(|&p| p as f64).collect();
+    let mut previous = played;
+    let started = Instant::now();
+
+    while played < options.games {
+        if options.time_limit.is_some_and(|limit| started.elapsed() >= limit) {
+            log(dir, &format!("Time limit reached after {} games", played));
+            break;
+        }
+        learner.ctx.set_evaluator(snapshot(options.kind, &params)?);
+        learner.new_game();
+        let mut rng = StdRng::seed_from_u64(options.seed.wrapping_add(played as u64));
+        let opening = random_opening(options.random_plies, &mut rng);
+        let (game, positions) = self_play(learner, opening);
+        let (b, w) = game.score();
+        let result = match b.cmp(&w) {
+            std::cmp::Ordering::Greater => 1.0,
+            std::cmp::Ordering::Less => 0.0,
+            std::cmp::Ordering::Equal => 0.5,
+        };
+        let (update, error) = td_update(tunable.as_mut(), &mut params, &positions, result, options.lambda);
+        for ((weight, param), u) in weights.iter_mut().zip(params.iter_mut()).zip(update) {
+            *weight += options.lr * u;
+            *param = weight.round() as i32;
+        }
+        tunable.set_params(&params);
+        played += 1;
+        println!("Game {}: {} - {}, td error {:.4}", played, b, w, error);
+
+        if played % options.checkpoint_every == 0 || played == options.games {
+            let path = checkpoint_path(dir, played);
+            save_checkpoint(tunable.as_ref(), &path)?;
+            log(dir, &format!("Checkpoint {} ({} games, {:.0} s)", path.display(), played, started.elapsed().as_secs_f64()));
+            if options.match_games > 0 {
+                // どのチェックポイントとも同じ序盤から先後を入れ替えて打つ
+                let mut rng = StdRng::seed_from_u64(!options.seed.wrapping_add(played as u64));
+                let openings: Vec<Game> =
+                    (0..options.match_games.div_ceil(2)).map(|_| random_opening(options.random_plies, &mut rng)).collect();
+                let mut rivals = vec![0];
+                if previous != 0 {
+                    rivals.push(previous);
+                }
+                for rival in rivals {
+                    learner.ctx.set_evaluator(snapshot(options.kind, &params)?);
+                    opponent.ctx.set_evaluator(make_evaluator(options.kind, Some(&checkpoint_path(dir, rival)))?);
+                    let r = arena::run_openings(learner, opponent, &openings);
+                    log(
+                        dir,
+                        &format!(
+                            "ckpt-{:06} vs ckpt-{:06}: Win {}, Lose {}, Draw {} (disc diff {:+})",
+                            played, rival, r.wins, r.losses, r.draws, r.discs
+                        ),
+                    );
+                }
+            }
+            previous = played;
+        }
+    }
+    if played != previous {
+        let path = checkpoint_path(dir, played);
+        save_checkpoint(tunable.as_ref(), &path)?;
+        log(dir, &format!("Checkpoint {} ({} games)", path.display(), played));
+    }
+    Ok(())
+}
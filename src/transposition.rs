@@ -2,6 +2,8 @@ use std::collections::HashMap;
 use once_cell::sync::Lazy;
 use std::sync::Mutex;
 
+use crate::bitboard::{Board, BuildZobristHasher};
+
 // 評価値の種類を定義
 #[derive(Clone, Copy, PartialEq)]
 pub enum NodeType {
@@ -18,34 +20,40 @@ pub struct TableEntry {
     pub node_type: NodeType,
 }
 
-// 置換表本体。スレッドセーフなHashMap。
+// 置換表本体。スレッドセーフなHashMap。キーは局面のZobristハッシュ値
+// ハッシュ値が衝突した別の局面の結果を使わないよう、局面 (手番側の石, 相手の石) も一緒に持って比べる
+#[allow(clippy::type_complexity)]
 pub struct TranspositionTable {
-    map: Mutex<HashMap<(u64, u64), TableEntry>>,
+    map: Mutex<HashMap<u64, ((u64, u64), TableEntry), BuildZobristHasher>>,
 }
 
 impl TranspositionTable {
     fn new() -> Self {
         TranspositionTable {
-            map: Mutex::new(HashMap::new()),
+            map: Mutex::new(HashMap::default()),
         }
     }
 
     /// データを格納する
-    pub fn store(&self, key: (u64, u64), entry: TableEntry) {
+    pub fn store(&self, board: &Board, entry: TableEntry) {
+        let position = (board.player(), board.opponent());
         let mut table = self.map.lock().unwrap();
-        // 既に登録されているデータが、今回格納するものより深い探索結果なら上書きしない
-        if let Some(existing_entry) = table.get(&key) {
-            if existing_entry.depth > entry.depth {
+        // 同じ局面の既に登録されているデータが、今回格納するものより深い探索結果なら上書きしない
+        if let Some((existing_position, existing_entry)) = table.get(&board.hash()) {
+            if *existing_position == position && existing_entry.depth > entry.depth {
                 return;
             }
         }
-        table.insert(key, entry);
+        table.insert(board.hash(), (position, entry));
     }
 
-    /// データを参照する
-    pub fn probe(&self, key: (u64, u64)) -> Option<TableEntry> {
+    /// データを参照する (ハッシュ値が同じでも局面が違えば見つからない)
+    pub fn probe(&self, board: &Board) -> Option<TableEntry> {
         let table = self.map.lock().unwrap();
-        table.get(&key).cloned()
+        table
+            .get(&board.hash())
+            .filter(|(position, _)| *position == (board.player(), board.opponent()))
+            .map(|(_, entry)| *entry)
     }
 
     /// テーブルをクリアする
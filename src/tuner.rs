@@ -0,0 +1,345 @@
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+
+use crate::bitboard::Board;
+use crate::dataset::Sample;
+use crate::evaluator::Evaluator;
+use crate::score::{self, Score};
+
+/// 自動調整できる評価関数。パラメータを整数の列として読み書きできる
+pub trait Tunable: Evaluator {
+    fn params(&self) -> Vec<i32>;
+    fn set_params(&mut self, params: &[i32]);
+    /// 重みから計算した値に掛ける倍率 (`Evaluator::scale`) を変える
+    fn set_scale(&mut self, scale: i32);
+    /// i番目のパラメータの名前 (表示用)
+    fn param_name(&self, i: usize) -> String;
+    /// パラメータをファイルから読み込む (`--eval-weights`)
+    fn load(&mut self, path: &Path) -> Result<(), String>;
+    /// `load`で読み込める形式で書き出す
+    fn save(&self, path: &Path) -> Result<(), String>;
+}
+
+/// 調整に使う局面。着手可能位置はパラメータによらないので先に求めておく
+pub struct Position {
+    pub board: Board,
+    player_moves: u64,
+    opponent_moves: u64,
+    /// 手番側から見た終局時の石差
+    pub target: f32,
+    /// 手番側から見た結果 (勝ち1, 引き分け0.5, 負け0)
+    pub result: f64,
+}
+
+impl Position {
+    pub fn new(sample: &Sample) -> Position {
+        let mut opponent = sample.board.clone();
+        opponent.exchange();
+        let result = if sample.target > 0.0 {
+            1.0
+        } else if sample.target < 0.0 {
+            0.0
+        } else {
+            0.5
+        };
+        Position {
+            board: sample.board.clone(),
+            player_moves: sample.board.legals().0,
+            opponent_moves: opponent.legals().0,
+            target: sample.target,
+            result,
+        }
+    }
+
+    pub fn evaluate(&self, evaluator: &dyn Evaluator) -> Score {
+        evaluator.evaluate(&self.board, self.player_moves, self.opponent_moves)
+    }
+}
+
+// 評価値を勝率に直す
+#[inline]
+fn sigmoid(eval: f64, k: f64) -> f64 {
+    1.0 / (1.0 + (-k * eval).exp())
+}
+
+// 勝率の予測と結果の二乗誤差の平均
+fn error(evaluator: &dyn Evaluator, positions: &[Position], k: f64) -> f64 {
+    if positions.is_empty() {
+        return 0.0;
+    }
+    let sum: f64 = positions.iter().map(|p| (p.result - sigmoid(p.evaluate(evaluator) as f64, k)).powi(2)).sum();
+    sum / positions.len() as f64
+}
+
+// 評価値と結果の組
+fn evaluations(evaluator: &dyn Evaluator, positions: &[Position]) -> Vec<(f64, f64)> {
+    positions.iter().map(|p| (p.evaluate(evaluator) as f64, p.result)).collect()
+}
+
+// 評価値と結果の組evalsで誤差が最小になるsigmoidの傾きk (評価値の尺度は評価関数ごとに違うので合わせる)
+fn fit_k(evals: &[(f64, f64)]) -> f64 {
+    let error = |k: f64| evals.iter().map(|&(e, r)| (r - sigmoid(e, k)).powi(2)).sum::<f64>();
+    // 10^-6 から 10^0 まで対数で粗く探し、最良の点の周りを細かく探す
+    let mut best = (-6.0, f64::MAX);
+    let mut step = 0.5;
+    let mut center = -3.0;
+    let mut range = 3.0;
+    while step > 0.001 {
+        let mut x = center - range;
+        while x <= center + range {
+            let e = error(10f64.powf(x));
+            if e < best.1 {
+                best = (x, e);
+            }
+            x += step;
+        }
+        center = best.0;
+        range = step;
+        step /= 5.0;
+    }
+    10f64.powf(best.0)
+}
+
+/// 評価値が終局時の石差の予測になるように、評価関数の出力の倍率を求める
+/// 評価値eと目標の石差の100倍tの二乗誤差が最小になる係数 Σet / Σe² を今の倍率scaleに掛ける (読み切った値は使わない)
+pub fn fit_scale(evals: impl Iterator<Item = (Score, f32)>, scale: i32) -> i32 {
+    let (mut et, mut ee) = (0.0, 0.0);
+    for (e, t) in evals.filter(|&(e, _)| !score::is_proven(e)) {
+        et += e as f64 * t as f64 * score::DISC as f64;
+        ee += (e as f64).powi(2);
+    }
+    if ee == 0.0 || et <= 0.0 {
+        return scale;
+    }
+    ((scale as f64 * et / ee).round() as i32).max(1)
+}
+
+/// 評価関数の出力の倍率と、評価値を勝率に直す傾きを局面と終局時の石差から求めて表示する
+/// 倍率は`fit_scale`、傾きはその倍率での評価値と勝敗の二乗誤差が最小になる値 (`score::K`と比べる)
+pub fn calibrate(evaluator: &dyn Evaluator, samples: &[Sample]) -> Result<(), String> {
+    // 読み切った値を返す局面を除いた (評価値, 局面)
+    let positions: Vec<Position> = samples.iter().map(Position::new).collect();
+    let evals: Vec<(Score, &Position)> = positions
+        .iter()
+        .map(|p| (p.evaluate(evaluator), p))
+        .filter(|&(e, _)| !score::is_proven(e))
+        .collect();
+    if evals.is_empty() {
+        return Err("no positions to calibrate on".to_string());
+    }
+    let scale = fit_scale(evals.iter().map(|&(e, p)| (e, p.target)), evaluator.scale());
+    let factor = scale as f64 / evaluator.scale() as f64;
+    // 倍率をfactor倍したときの評価値と石差の二乗平均平方根 (石)
+    let rms = |factor: f64| {
+        let sum: f64 = evals.iter().map(|&(e, p)| (e as f64 * factor / score::DISC as f64 - p.target as f64).powi(2)).sum();
+        (sum / evals.len() as f64).sqrt()
+    };
+    println!("Positions: {}", evals.len());
+    println!("scale {} -> {} (rms error {:.2} -> {:.2} discs)", evaluator.scale(), scale, rms(1.0), rms(factor));
+
+    let results: Vec<(f64, f64)> = evals.iter().map(|&(e, p)| (e as f64 * factor, p.result)).collect();
+    let k = fit_k(&results);
+    let error = |k: f64| results.iter().map(|&(e, r)| (r - sigmoid(e, k)).powi(2)).sum::<f64>() / results.len() as f64;
+    println!("k = {:.6}, error {:.5} (score::K = {}: {:.5})", k, error(k), score::K, error(score::K));
+
+    // 合わせた評価値を5石ごとに分け、実際の石差の平均と勝率を予測と並べる
+    println!("{:>12} {:>9} {:>12} {:>9} {:>9}", "eval", "positions", "mean result", "win rate", "predicted");
+    let mut buckets = [(0usize, 0.0, 0.0, 0.0); 12];
+    for (&(_, p), &(e, r)) in evals.iter().zip(results.iter()) {
+        let i = ((e / score::DISC as f64 / 5.0).floor() as i32).clamp(-6, 5) + 6;
+        let bucket = &mut buckets[i as usize];
+        *bucket = (bucket.0 + 1, bucket.1 + p.target as f64, bucket.2 + r, bucket.3 + sigmoid(e, score::K));
+    }
+    for (i, &(n, target, result, predicted)) in buckets.iter().enumerate().filter(|(_, b)| b.0 > 0) {
+        let from = (i as i32 - 6) * 5;
+        let range = match i {
+            0 => format!("< {}", from + 5),
+            11 => format!(">= {}", from),
+            _ => format!("{}..{}", from, from + 5),
+        };
+        let n_f = n as f64;
+        println!("{:>12} {:>9} {:>+12.2} {:>8.1}% {:>8.1}%", range, n, target / n_f, result / n_f * 100.0, predicted / n_f * 100.0);
+    }
+    Ok(())
+}
+
+// 重みを書き出し、調整に使った傾きkをコメントとして書き足す
+fn save(evaluator: &dyn Tunable, out: &Path, k: f64) -> Result<(), String> {
+    evaluator.save(out)?;
+    let mut file = OpenOptions::new().append(true).open(out).map_err(|e| format!("{}: {}", out.display(), e))?;
+    writeln!(file, "# k {:.6}", k).map_err(|e| format!("{}: {}", out.display(), e))
+}
+
+/// Texel法でパラメータを調整する
+/// 局面ごとに評価値をsigmoidで勝率に直し、実際の勝敗との二乗誤差が減る方へパラメータを一つずつ動かす
+/// sigmoidの傾きkは最初の重みで誤差が最小になる値に合わせ、調整の間は変えない
+/// 一割の局面は検証用に取っておき、その誤差も表示する。一周するたびにoutへ書き出す (kも`# k`の行に書く)
+pub fn tune(evaluator: &mut dyn Tunable, samples: &[Sample], out: &Path, iterations: usize, seed: u64) -> Result<(), String> {
+    if samples.is_empty() {
+        return Err("no training positions".to_string());
+    }
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut positions: Vec<Position> = samples.iter().map(Position::new).collect();
+    for i in (1..positions.len()).rev() {
+        positions.swap(i, rng.gen_range(0..=i));
+    }
+    let validation = positions.split_off(positions.len() - positions.len() / 10);
+    println!("Training positions: {}, validation positions: {}", positions.len(), validation.len());
+
+    let k = fit_k(&evaluations(evaluator, &positions));
+    let mut params = evaluator.params();
+    let initial_params = params.clone();
+    // パラメータごとの刻み幅。改善しなければ半分にし、どれも1で改善しなくなったら止める
+    let mut steps: Vec<i32> = params.iter().map(|p| (p.abs() / 8).max(1)).collect();
+    let mut best = error(evaluator, &positions, k);
+    println!("k = {:.6}, error {:.5} (validation {:.5})", k, best, error(evaluator, &validation, k));
+
+    for iteration in 1..=iterations {
+        let mut changed = 0;
+        for i in 0..params.len() {
+            let original = params[i];
+            let mut improved = false;
+            for delta in [steps[i], -steps[i]] {
+                params[i] = original + delta;
+                evaluator.set_params(&params);
+                let e = error(evaluator, &positions, k);
+                if e < best {
+                    best = e;
+                    improved = true;
+                    break;
+                }
+            }
+            if improved {
+                changed += 1;
+            } else {
+                params[i] = original;
+                evaluator.set_params(&params);
+                steps[i] = (steps[i] / 2).max(1);
+            }
+        }
+        println!(
+            "iteration {}: error {:.5} (validation {:.5}), {} changed",
+            iteration,
+            best,
+            error(evaluator, &validation, k),
+            changed
+        );
+        save(evaluator, out, k)?;
+        if changed == 0 && steps.iter().all(|&s| s == 1) {
+            break;
+        }
+    }
+    // 最後に倍率を合わせる。kもその倍率の評価値に対する傾きに直して書く
+    let scale = fit_scale(positions.iter().map(|p| (p.evaluate(evaluator), p.target)), evaluator.scale());
+    println!("scale {} -> {}", evaluator.scale(), scale);
+    let k = k * evaluator.scale() as f64 / scale as f64;
+    evaluator.set_scale(scale);
+    save(evaluator, out, k)?;
+    for (i, (before, after)) in initial_params.iter().zip(params.iter()).enumerate() {
+        if before != after {
+            println!("{:<28} {:>6} -> {:>6}", evaluator.param_name(i), before, after);
+        }
+    }
+    println!("Wrote {}", out.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cmds::Color;
+    use crate::evaluator::Heuristic;
+    use crate::game::Game;
+    use std::fs;
+
+    // ランダムに打った対局の全局面に、手番側から見た終局時の石差を付ける
+    fn random_samples(games: usize, seed: u64) -> Vec<Sample> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut samples = Vec::new();
+        for _ in 0..games {
+            let mut game = Game::new();
+            let mut boards = Vec::new();
+            while !game.is_over() {
+                boards.push(game.board().clone());
+                let legals = game.board().legals().0;
+                let mv = if legals == 0 {
+                    0
+                } else {
+                    let squares: Vec<u64> = (0..64).map(|i| 1 << i).filter(|b| legals & b != 0).collect();
+                    squares[rng.gen_range(0..squares.len())]
+                };
+                game.play(mv).unwrap();
+            }
+            let (black, white) = game.score();
+            let diff = black as f32 - white as f32;
+            for board in boards {
+                let target = if board.side() == Color::Black { diff } else { -diff };
+                samples.push(Sample { board, target });
+            }
+        }
+        samples
+    }
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("reversi-test-{}-{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn fitted_k_minimizes_the_error() {
+        let heuristic = Heuristic::new();
+        let positions: Vec<Position> = random_samples(200, 1).iter().map(Position::new).collect();
+        let k = fit_k(&evaluations(&heuristic, &positions));
+        let best = error(&heuristic, &positions, k);
+        assert!(k > 1e-6 && k < 1.0);
+        assert!(best < error(&heuristic, &positions, 0.0));
+        assert!(best <= error(&heuristic, &positions, k * 1.5));
+        assert!(best <= error(&heuristic, &positions, k / 1.5));
+    }
+
+    #[test]
+    fn tuned_weights_are_written_with_k() {
+        let path = temp_path("tuned.txt");
+        let mut heuristic = Heuristic::new();
+        tune(&mut heuristic, &random_samples(50, 2), &path, 1, 0).unwrap();
+        let text = fs::read_to_string(&path).unwrap();
+        let mut loaded = Heuristic::new();
+        loaded.load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let k: f64 = text.lines().find_map(|line| line.strip_prefix("# k ")).unwrap().parse().unwrap();
+        assert!(k > 0.0);
+        assert_eq!(loaded.params(), heuristic.params());
+        assert_eq!(loaded.scale(), heuristic.scale());
+    }
+
+    #[test]
+    fn fit_scale_recovers_a_known_factor() {
+        // 目標の石差が評価値の3倍 (評価値100が3石) なら倍率も3倍になる。読み切った値は使わない
+        let mut evals: Vec<(Score, f32)> = (-20..=20).map(|e| (e * 37, (e * 37 * 3) as f32 / 100.0)).collect();
+        evals.push((score::proven(10), -64.0));
+        assert_eq!(fit_scale(evals.iter().copied(), score::SCALE_ONE), 3 * score::SCALE_ONE);
+        assert_eq!(fit_scale(evals.iter().copied(), 512), 3 * 512);
+    }
+
+    #[test]
+    fn calibrated_heuristic_predicts_the_final_disc_difference() {
+        let samples = random_samples(200, 3);
+        let positions: Vec<Position> = samples.iter().map(Position::new).collect();
+        let evals = |h: &Heuristic| -> Vec<(Score, f32)> { positions.iter().map(|p| (p.evaluate(h), p.target)).collect() };
+        // 評価値と石差の100倍の差の二乗和
+        let loss = |evals: &[(Score, f32)]| -> f64 {
+            evals.iter().filter(|(e, _)| !score::is_proven(*e)).map(|&(e, t)| (e as f64 - t as f64 * 100.0).powi(2)).sum()
+        };
+        let mut heuristic = Heuristic::new();
+        heuristic.set_scale(score::SCALE_ONE);
+        let before = evals(&heuristic);
+        heuristic.set_scale(fit_scale(before.iter().copied(), score::SCALE_ONE));
+        let after = evals(&heuristic);
+        assert!(loss(&after) < loss(&before));
+        // 合わせた後の評価値をもう一度合わせても倍率はほとんど変わらない
+        let refit = fit_scale(after.iter().copied(), heuristic.scale());
+        assert!((refit - heuristic.scale()).abs() <= heuristic.scale() / 100);
+    }
+}
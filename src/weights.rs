@@ -0,0 +1,130 @@
+use std::fs;
+use std::path::Path;
+
+use crate::score::SCALE_ONE;
+
+// 重みを決める3つの段階の空きマスの数 (序盤・中盤・終盤)
+// この間の局面では線形に補間し、序盤より前は序盤の、終盤より後は終盤の重みを使う
+const PHASE_EMPTIES: [usize; 3] = [50, 32, 14];
+
+/// 評価項目ごとの重みを序盤・中盤・終盤の3段階で持ち、空きマスの数で補間する
+/// 重みから計算した値に掛ける倍率 (`score::scaled`) も一緒に持つ
+/// ファイルの形式は一行に一項目で `名前 序盤 中盤 終盤` と `scale 倍率` (`#`以降はコメント)。書かれていない項目は元の値のまま
+pub struct PhaseWeights {
+    names: &'static [&'static str],
+    phases: Vec<[i32; 3]>,
+    scale: i32,
+    // 空きマスの数ごとに補間した重み ([空きマス][項目])
+    by_empties: Vec<Vec<i32>>,
+}
+
+impl PhaseWeights {
+    /// names[i]の重みをphases[i] (序盤, 中盤, 終盤) とし、倍率をscale (`SCALE_ONE`で1倍) とする
+    pub fn new(names: &'static [&'static str], phases: &[[i32; 3]], scale: i32) -> PhaseWeights {
+        debug_assert_eq!(names.len(), phases.len());
+        let mut weights = PhaseWeights { names, phases: phases.to_vec(), scale, by_empties: Vec::new() };
+        weights.interpolate();
+        weights
+    }
+
+    fn interpolate(&mut self) {
+        self.by_empties = (0..=64)
+            .map(|empties| self.phases.iter().map(|p| interpolate(p, empties)).collect())
+            .collect();
+    }
+
+    /// 空きマスがempties個の局面での重み (namesの順)
+    #[inline]
+    pub fn at(&self, empties: usize) -> &[i32] {
+        &self.by_empties[empties]
+    }
+
+    /// 重みから計算した値に掛ける倍率 (`SCALE_ONE`で1倍)
+    #[inline]
+    pub fn scale(&self) -> i32 {
+        self.scale
+    }
+
+    pub fn set_scale(&mut self, scale: i32) {
+        self.scale = scale;
+    }
+
+    pub fn load(&mut self, path: &Path) -> Result<(), String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("");
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.is_empty() {
+                continue;
+            }
+            let error = |msg: String| format!("{}:{}: {}", path.display(), i + 1, msg);
+            if fields[0] == "scale" {
+                self.scale = match fields[1..] {
+                    [scale] => scale.parse().ok().filter(|&s| s > 0).ok_or_else(|| error(format!("invalid scale '{}'", scale)))?,
+                    _ => return Err(error("expected 'scale <n>'".to_string())),
+                };
+                continue;
+            }
+            let idx = self
+                .names
+                .iter()
+                .position(|&name| name == fields[0])
+                .ok_or_else(|| error(format!("unknown weight '{}' (expected one of {})", fields[0], self.names.join(", "))))?;
+            if fields.len() != 4 {
+                return Err(error(format!("expected '{} <opening> <midgame> <late>'", fields[0])));
+            }
+            for phase in 0..3 {
+                self.phases[idx][phase] = fields[phase + 1]
+                    .parse()
+                    .map_err(|_| error(format!("invalid number '{}'", fields[phase + 1])))?;
+            }
+        }
+        self.interpolate();
+        Ok(())
+    }
+
+    /// `load`で読める形式で書き出す
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let mut s = format!("# scale ({} = 1x)\nscale {}\n# name opening midgame late\n", SCALE_ONE, self.scale);
+        for (name, p) in self.names.iter().zip(self.phases.iter()) {
+            s += &format!("{} {} {} {}\n", name, p[0], p[1], p[2]);
+        }
+        fs::write(path, s).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    /// 全項目の3段階の重みを一列に並べたもの (項目ごとに 序盤, 中盤, 終盤)
+    pub fn params(&self) -> Vec<i32> {
+        self.phases.iter().flatten().copied().collect()
+    }
+
+    /// `params`と同じ並びの値で重みを置き換える
+    pub fn set_params(&mut self, params: &[i32]) {
+        for (phase, chunk) in self.phases.iter_mut().zip(params.chunks(3)) {
+            phase.copy_from_slice(chunk);
+        }
+        self.interpolate();
+    }
+
+    /// `params`のi番目の名前 (`項目:段階`)
+    pub fn param_name(&self, i: usize) -> String {
+        format!("{}:{}", self.names[i / 3], ["opening", "midgame", "late"][i % 3])
+    }
+}
+
+// 空きマスの数で3段階の重みを線形に補間する
+fn interpolate(phase: &[i32; 3], empties: usize) -> i32 {
+    let [opening, midgame, late] = PHASE_EMPTIES;
+    let lerp = |a: i32, b: i32, from: usize, to: usize| {
+        // emptiesがfromのときa、toのときb
+        a + (b - a) * (from - empties) as i32 / (from - to) as i32
+    };
+    if empties >= opening {
+        phase[0]
+    } else if empties >= midgame {
+        lerp(phase[0], phase[1], opening, midgame)
+    } else if empties >= late {
+        lerp(phase[1], phase[2], midgame, late)
+    } else {
+        phase[2]
+    }
+}